@@ -0,0 +1,1038 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::prelude::*;
+use hiercmd::prelude::*;
+use keeper_common::*;
+use keeper_openapi::{types, Client};
+use keeper_store::{
+    KeyStore, ReportOutcome, ReportStore, Schedule, Termination,
+};
+use serde::Deserialize;
+use slog::{o, Logger};
+
+/**
+ * The administrative tool uses the same configuration file as
+ * "keeper-submit", though the key therein must have been marked as an
+ * administrative key on the server.
+ */
+#[derive(Deserialize)]
+struct ConfigFile {
+    baseurl: String,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    client_cert: Option<PathBuf>,
+    #[serde(default)]
+    client_key: Option<PathBuf>,
+    #[serde(default)]
+    ca_file: Option<PathBuf>,
+}
+
+fn make_client() -> Result<Client> {
+    let path = if let Some(mut home) = dirs::home_dir() {
+        home.push(".keeper.json");
+        home
+    } else {
+        bail!("could not find home directory");
+    };
+
+    let cf: ConfigFile = load_file(&path)?.ok_or_else(|| {
+        anyhow!("no configuration file at {}; enrol first", path.display())
+    })?;
+
+    let mut b = keeper_openapi::ClientBuilder::new(&cf.baseurl);
+    match (&cf.key, &cf.client_cert, &cf.client_key) {
+        (Some(key), None, None) => {
+            b.bearer_token(key);
+        }
+        (None, Some(cert), Some(key)) => {
+            b.client_cert(cert, key);
+        }
+        _ => bail!(
+            "configuration must have either a key, or a client certificate \
+            and key, but not both"
+        ),
+    }
+    if let Some(ca_file) = &cf.ca_file {
+        b.ca_file(ca_file);
+    }
+    b.build()
+}
+
+/**
+ * Most commands can operate either through the server API, or directly on
+ * the data directory of a server.  The latter is useful for bootstrapping,
+ * or when the server is not running.
+ */
+enum Target {
+    Api(Client),
+    Dir(KeyStore, ReportStore),
+}
+
+fn opt_dir(l: &mut Level<()>) {
+    l.optopt(
+        "d",
+        "",
+        "operate directly on a data directory, rather than through the API",
+        "DIRECTORY",
+    );
+}
+
+fn target(dir: Option<String>) -> Result<Target> {
+    if let Some(dir) = dir {
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            bail!("{} should be a directory", dir.display());
+        }
+
+        let log = Logger::root(slog::Discard, o!());
+        Ok(Target::Dir(
+            KeyStore::new(log.clone(), &dir)?,
+            ReportStore::new(log, &dir)?,
+        ))
+    } else {
+        Ok(Target::Api(make_client()?))
+    }
+}
+
+fn fmt_time(t: &DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn yes_no(v: bool) -> &'static str {
+    if v {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut l = Level::new("keeper-admin", ());
+
+    l.cmd("enrol", "manage pending host enrolments", cmd!(cmd_enrol))?;
+    l.cmd("host", "manage confirmed hosts", cmd!(cmd_host))?;
+    l.cmd("job", "inspect jobs", cmd!(cmd_job))?;
+    l.cmd(
+        "schedule",
+        "manage the expected schedules of jobs",
+        cmd!(cmd_schedule),
+    )?;
+    l.cmd("report", "inspect job reports", cmd!(cmd_report))?;
+    l.cmd("prune", "remove old job reports", cmd!(cmd_prune))?;
+
+    sel!(l).run().await
+}
+
+async fn cmd_enrol(mut l: Level<()>) -> Result<()> {
+    l.cmd("list", "list pending enrolments", cmd!(cmd_enrol_list))?;
+    l.cmd(
+        "approve",
+        "approve the pending enrolment of a host",
+        cmd!(cmd_enrol_approve),
+    )?;
+    l.cmd(
+        "reject",
+        "reject the pending enrolment of a host",
+        cmd!(cmd_enrol_reject),
+    )?;
+
+    sel!(l).run().await
+}
+
+async fn cmd_enrol_list(mut l: Level<()>) -> Result<()> {
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if !a.args().is_empty() {
+        bad_args!(l, "unexpected arguments");
+    }
+
+    let enrolments = match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => c
+            .admin_enrolments()
+            .send()
+            .await?
+            .into_inner()
+            .enrolments
+            .into_iter()
+            .map(|e| (e.host, e.time_create))
+            .collect::<Vec<_>>(),
+        Target::Dir(keys, _) => keys
+            .list_enrolments()?
+            .into_iter()
+            .map(|e| (e.host, e.time_create))
+            .collect::<Vec<_>>(),
+    };
+
+    println!("{:<24} REQUESTED", "HOST");
+    for (host, time_create) in enrolments.iter() {
+        println!("{:<24} {}", host, fmt_time(time_create));
+    }
+
+    Ok(())
+}
+
+async fn cmd_enrol_approve(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST..."));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().is_empty() {
+        bad_args!(l, "specify at least one host to approve");
+    }
+
+    let t = target(a.opts().opt_str("d"))?;
+    for host in a.args() {
+        match &t {
+            Target::Api(c) => {
+                c.admin_enrolment_approve().host(host).send().await?;
+            }
+            Target::Dir(keys, _) => {
+                if !keys.approve_enrolment(host)? {
+                    bail!("no pending enrolment for {}", host);
+                }
+            }
+        }
+        println!("approved {}", host);
+    }
+
+    Ok(())
+}
+
+async fn cmd_enrol_reject(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST..."));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().is_empty() {
+        bad_args!(l, "specify at least one host to reject");
+    }
+
+    let t = target(a.opts().opt_str("d"))?;
+    for host in a.args() {
+        match &t {
+            Target::Api(c) => {
+                c.admin_enrolment_reject().host(host).send().await?;
+            }
+            Target::Dir(keys, _) => {
+                if !keys.reject_enrolment(host)? {
+                    bail!("no pending enrolment for {}", host);
+                }
+            }
+        }
+        println!("rejected {}", host);
+    }
+
+    Ok(())
+}
+
+async fn cmd_host(mut l: Level<()>) -> Result<()> {
+    l.cmd("list", "list confirmed hosts", cmd!(cmd_host_list))?;
+    l.cmd(
+        "global-view",
+        "grant or remove the global view privilege",
+        cmd!(cmd_host_global_view),
+    )?;
+    l.cmd("revoke", "revoke all keys of a host", cmd!(cmd_host_revoke))?;
+    l.cmd("keys", "list the keys of a host", cmd!(cmd_host_keys))?;
+    l.cmd(
+        "revoke-key",
+        "revoke a single key of a host",
+        cmd!(cmd_host_revoke_key),
+    )?;
+    l.cmd(
+        "reload",
+        "ask the server to reload key files from disk",
+        cmd!(cmd_host_reload),
+    )?;
+
+    sel!(l).run().await
+}
+
+async fn cmd_host_list(mut l: Level<()>) -> Result<()> {
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if !a.args().is_empty() {
+        bad_args!(l, "unexpected arguments");
+    }
+
+    let hosts = match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => c
+            .admin_hosts()
+            .send()
+            .await?
+            .into_inner()
+            .hosts
+            .into_iter()
+            .map(|h| (h.host, h.time_create, h.global_view, h.admin))
+            .collect::<Vec<_>>(),
+        Target::Dir(keys, _) => keys
+            .list_hosts()?
+            .into_iter()
+            .map(|h| (h.host, h.time_create, h.global_view, h.admin))
+            .collect::<Vec<_>>(),
+    };
+
+    println!("{:<24} {:<20} {:<6} ADMIN", "HOST", "CREATED", "GLOBAL");
+    for (host, time_create, global_view, admin) in hosts.iter() {
+        println!(
+            "{:<24} {:<20} {:<6} {}",
+            host,
+            fmt_time(time_create),
+            yes_no(*global_view),
+            yes_no(*admin),
+        );
+    }
+
+    Ok(())
+}
+
+async fn cmd_host_global_view(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST on|off"));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() != 2 {
+        bad_args!(l, "specify a host, and whether to turn global view on");
+    }
+    let host = a.args()[0].as_str();
+    let global_view = match a.args()[1].as_str() {
+        "on" => true,
+        "off" => false,
+        other => bail!("expected \"on\" or \"off\", not {:?}", other),
+    };
+
+    match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => {
+            c.admin_host_update()
+                .host(host)
+                .body(types::HostUpdateBody {
+                    global_view: Some(global_view),
+                })
+                .send()
+                .await?;
+        }
+        Target::Dir(keys, _) => {
+            if !keys.set_global_view(host, global_view)? {
+                bail!("no confirmed key for {}", host);
+            }
+        }
+    }
+
+    println!("global view for {} is {}", host, a.args()[1]);
+    Ok(())
+}
+
+async fn cmd_host_revoke(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST..."));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().is_empty() {
+        bad_args!(l, "specify at least one host to revoke");
+    }
+
+    let t = target(a.opts().opt_str("d"))?;
+    for host in a.args() {
+        match &t {
+            Target::Api(c) => {
+                c.admin_host_revoke().host(host).send().await?;
+            }
+            Target::Dir(keys, _) => {
+                if !keys.revoke_host(host)? {
+                    bail!("no confirmed key for {}", host);
+                }
+            }
+        }
+        println!("revoked {}", host);
+    }
+
+    Ok(())
+}
+
+async fn cmd_host_keys(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST"));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() != 1 {
+        bad_args!(l, "specify a host");
+    }
+    let host = a.args()[0].as_str();
+
+    let keys = match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => c
+            .admin_host_keys()
+            .host(host)
+            .send()
+            .await?
+            .into_inner()
+            .keys
+            .into_iter()
+            .map(|k| {
+                (k.id, k.time_create, k.time_expire, k.time_revoke, k.valid)
+            })
+            .collect::<Vec<_>>(),
+        Target::Dir(keys, _) => keys
+            .list_keys(host)?
+            .ok_or_else(|| anyhow!("no confirmed key for {}", host))?
+            .into_iter()
+            .map(|k| {
+                (k.id, k.time_create, k.time_expire, k.time_revoke, k.valid)
+            })
+            .collect::<Vec<_>>(),
+    };
+
+    println!(
+        "{:<8} {:<20} {:<20} {:<20} VALID",
+        "ID", "CREATED", "EXPIRES", "REVOKED"
+    );
+    for (id, time_create, time_expire, time_revoke, valid) in keys.iter() {
+        println!(
+            "{:<8} {:<20} {:<20} {:<20} {}",
+            id,
+            fmt_time(time_create),
+            time_expire
+                .as_ref()
+                .map(fmt_time)
+                .unwrap_or_else(|| "-".into()),
+            time_revoke
+                .as_ref()
+                .map(fmt_time)
+                .unwrap_or_else(|| "-".into()),
+            yes_no(*valid),
+        );
+    }
+
+    Ok(())
+}
+
+async fn cmd_host_revoke_key(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST ID..."));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() < 2 {
+        bad_args!(l, "specify a host, and at least one key ID to revoke");
+    }
+    let host = a.args()[0].as_str();
+
+    let t = target(a.opts().opt_str("d"))?;
+    for id in a.args().iter().skip(1) {
+        match &t {
+            Target::Api(c) => {
+                c.admin_host_key_revoke().host(host).id(id).send().await?;
+            }
+            Target::Dir(keys, _) => {
+                if !keys.revoke_key(host, id)? {
+                    bail!("no key {} for {}", id, host);
+                }
+            }
+        }
+        println!("revoked key {} for {}", id, host);
+    }
+
+    Ok(())
+}
+
+async fn cmd_host_reload(mut l: Level<()>) -> Result<()> {
+    let a = args!(l);
+    if !a.args().is_empty() {
+        bad_args!(l, "unexpected arguments");
+    }
+
+    let c = make_client()?;
+    let res = c.admin_keys_reload().send().await?;
+
+    println!("server loaded {} keys", res.keys);
+    Ok(())
+}
+
+async fn cmd_job(mut l: Level<()>) -> Result<()> {
+    l.cmd(
+        "list",
+        "list jobs, with the result of the most recent run",
+        cmd!(cmd_job_list),
+    )?;
+    l.cmd(
+        "running",
+        "list jobs that have started but not yet finished",
+        cmd!(cmd_job_running),
+    )?;
+
+    sel!(l).run().await
+}
+
+async fn cmd_job_running(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("[HOST]"));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() > 1 {
+        bad_args!(l, "specify at most one host");
+    }
+    let host = a.args().first();
+
+    let running = match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => c
+            .global_running()
+            .send()
+            .await?
+            .into_inner()
+            .running
+            .into_iter()
+            .map(|r| {
+                (
+                    r.host,
+                    r.job,
+                    r.start_time,
+                    r.running_seconds,
+                    r.overlapping,
+                )
+            })
+            .collect::<Vec<_>>(),
+        Target::Dir(_, reports) => reports
+            .running()?
+            .into_iter()
+            .map(|r| {
+                (
+                    r.host,
+                    r.job,
+                    r.start_time,
+                    r.running_seconds,
+                    r.overlapping,
+                )
+            })
+            .collect::<Vec<_>>(),
+    };
+
+    println!(
+        "{:<24} {:<24} {:<20} {:>8} {:>7}",
+        "HOST", "JOB", "STARTED", "RUNNING", "OVERLAP"
+    );
+    for (h, job, start, secs, overlapping) in running.iter() {
+        if host.map(|host| host != h).unwrap_or(false) {
+            continue;
+        }
+
+        println!(
+            "{:<24} {:<24} {:<20} {:>7}s {:>7}",
+            h,
+            job,
+            fmt_time(start),
+            secs,
+            overlapping,
+        );
+    }
+
+    Ok(())
+}
+
+async fn cmd_job_list(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("[HOST]"));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() > 1 {
+        bad_args!(l, "specify at most one host");
+    }
+    let host = a.args().first();
+
+    let jobs = match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => c
+            .global_jobs()
+            .send()
+            .await?
+            .into_inner()
+            .summary
+            .into_iter()
+            .map(|j| {
+                let outcome = match j.outcome {
+                    types::ReportOutcome::Completed => None,
+                    types::ReportOutcome::TimedOut => Some("timeout"),
+                    types::ReportOutcome::Skipped => Some("skipped"),
+                    types::ReportOutcome::Abandoned => Some("abandoned"),
+                };
+                (
+                    j.host,
+                    j.job,
+                    j.when,
+                    j.status,
+                    j.duration_seconds,
+                    outcome,
+                    j.skipped,
+                )
+            })
+            .collect::<Vec<_>>(),
+        Target::Dir(_, reports) => reports
+            .summary(1)?
+            .into_iter()
+            .map(|j| {
+                let outcome = match j.outcome {
+                    ReportOutcome::Completed => None,
+                    ReportOutcome::TimedOut => Some("timeout"),
+                    ReportOutcome::Skipped => Some("skipped"),
+                    ReportOutcome::Abandoned => Some("abandoned"),
+                };
+                (
+                    j.host,
+                    j.job,
+                    j.when,
+                    j.status,
+                    j.duration_seconds,
+                    outcome,
+                    j.skipped,
+                )
+            })
+            .collect::<Vec<_>>(),
+    };
+
+    println!(
+        "{:<24} {:<24} {:<20} {:>6} {:>8}",
+        "HOST", "JOB", "LAST RUN", "STATUS", "DURATION"
+    );
+    for (h, job, when, status, duration, outcome, skipped) in jobs.iter() {
+        if host.map(|host| host != h).unwrap_or(false) {
+            continue;
+        }
+
+        let status = if let Some(outcome) = outcome {
+            outcome.to_string()
+        } else {
+            status.to_string()
+        };
+
+        /*
+         * A skipped run is only shown in place of the last real result if
+         * there is no real result to show.
+         */
+        let note = if *skipped && outcome != &Some("skipped") {
+            " (latest run skipped)"
+        } else {
+            ""
+        };
+
+        println!(
+            "{:<24} {:<24} {:<20} {:>6} {:>7}s{}",
+            h,
+            job,
+            fmt_time(when),
+            status,
+            duration,
+            note,
+        );
+    }
+
+    Ok(())
+}
+
+async fn cmd_schedule(mut l: Level<()>) -> Result<()> {
+    l.cmd(
+        "list",
+        "list expected schedules, and whether jobs are overdue",
+        cmd!(cmd_schedule_list),
+    )?;
+    l.cmd(
+        "set",
+        "set the expected schedule of a job",
+        cmd!(cmd_schedule_set),
+    )?;
+    l.cmd(
+        "remove",
+        "remove the expected schedule of a job",
+        cmd!(cmd_schedule_remove),
+    )?;
+
+    sel!(l).run().await
+}
+
+fn fmt_schedule(cron: Option<&str>, interval_seconds: Option<u64>) -> String {
+    if let Some(cron) = cron {
+        format!("cron {:?}", cron)
+    } else if let Some(interval) = interval_seconds {
+        format!("every {}s", interval)
+    } else {
+        "-".to_string()
+    }
+}
+
+async fn cmd_schedule_list(mut l: Level<()>) -> Result<()> {
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if !a.args().is_empty() {
+        bad_args!(l, "unexpected arguments");
+    }
+
+    let schedules = match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => c
+            .admin_schedules()
+            .send()
+            .await?
+            .into_inner()
+            .schedules
+            .into_iter()
+            .map(|s| {
+                (
+                    s.host,
+                    s.job,
+                    fmt_schedule(
+                        s.schedule.cron.as_deref(),
+                        s.schedule.interval_seconds,
+                    ),
+                    s.schedule.grace_seconds,
+                    s.last_run,
+                    s.deadline,
+                    s.overdue,
+                )
+            })
+            .collect::<Vec<_>>(),
+        Target::Dir(_, reports) => reports
+            .schedule_status()?
+            .into_iter()
+            .map(|s| {
+                (
+                    s.host,
+                    s.job,
+                    fmt_schedule(
+                        s.schedule.cron.as_deref(),
+                        s.schedule.interval_seconds,
+                    ),
+                    s.schedule.grace_seconds,
+                    s.last_run,
+                    s.deadline,
+                    s.overdue,
+                )
+            })
+            .collect::<Vec<_>>(),
+    };
+
+    println!(
+        "{:<24} {:<24} {:<20} {:<20} {:<7} SCHEDULE",
+        "HOST", "JOB", "LAST RUN", "DEADLINE", "OVERDUE"
+    );
+    for (host, job, sched, grace, last_run, deadline, overdue) in
+        schedules.iter()
+    {
+        println!(
+            "{:<24} {:<24} {:<20} {:<20} {:<7} {} (grace {}s)",
+            host,
+            job,
+            last_run
+                .as_ref()
+                .map(fmt_time)
+                .unwrap_or_else(|| "-".into()),
+            fmt_time(deadline),
+            yes_no(*overdue),
+            sched,
+            grace,
+        );
+    }
+
+    Ok(())
+}
+
+async fn cmd_schedule_set(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST JOB"));
+    l.optopt("c", "", "cron expression (in UTC)", "EXPRESSION");
+    l.optopt("i", "", "maximum interval between runs", "SECONDS");
+    l.optopt("g", "", "grace period before a job is overdue", "SECONDS");
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() != 2 {
+        bad_args!(l, "specify a host and a job");
+    }
+    let host = a.args()[0].as_str();
+    let job = a.args()[1].as_str();
+
+    let cron = a.opts().opt_str("c");
+    let interval_seconds = if let Some(i) = a.opts().opt_str("i") {
+        if let Ok(i) = i.parse::<u64>() {
+            Some(i)
+        } else {
+            bad_args!(l, "interval must be a positive integer");
+        }
+    } else {
+        None
+    };
+    let grace_seconds = if let Some(g) = a.opts().opt_str("g") {
+        if let Ok(g) = g.parse::<u64>() {
+            g
+        } else {
+            bad_args!(l, "grace period must be a positive integer");
+        }
+    } else {
+        0
+    };
+    if cron.is_some() == interval_seconds.is_some() {
+        bad_args!(l, "specify exactly one of -c or -i");
+    }
+
+    let sched = Schedule {
+        cron,
+        interval_seconds,
+        grace_seconds,
+    };
+    sched.check()?;
+
+    match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => {
+            c.admin_schedule_set()
+                .host(host)
+                .job(job)
+                .body(types::Schedule {
+                    cron: sched.cron.clone(),
+                    interval_seconds: sched.interval_seconds,
+                    grace_seconds: sched.grace_seconds,
+                })
+                .send()
+                .await?;
+        }
+        Target::Dir(_, reports) => {
+            reports.set_schedule(host, job, &sched)?;
+        }
+    }
+
+    println!(
+        "schedule for {}/{} is {} (grace {}s)",
+        host,
+        job,
+        fmt_schedule(sched.cron.as_deref(), sched.interval_seconds),
+        sched.grace_seconds,
+    );
+    Ok(())
+}
+
+async fn cmd_schedule_remove(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST JOB"));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() != 2 {
+        bad_args!(l, "specify a host and a job");
+    }
+    let host = a.args()[0].as_str();
+    let job = a.args()[1].as_str();
+
+    match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => {
+            c.admin_schedule_remove().host(host).job(job).send().await?;
+        }
+        Target::Dir(_, reports) => {
+            if !reports.remove_schedule(host, job)? {
+                bail!("no schedule for {}/{}", host, job);
+            }
+        }
+    }
+
+    println!("removed schedule for {}/{}", host, job);
+    Ok(())
+}
+
+async fn cmd_report(mut l: Level<()>) -> Result<()> {
+    l.cmd(
+        "show",
+        "show a job report and its output",
+        cmd!(cmd_report_show),
+    )?;
+
+    sel!(l).run().await
+}
+
+async fn cmd_report_show(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("HOST JOB TIME"));
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() != 3 {
+        bad_args!(
+            l,
+            "specify a host, a job, and a report time in milliseconds"
+        );
+    }
+    let host = a.args()[0].as_str();
+    let job = a.args()[1].as_str();
+    let time = if let Ok(time) = a.args()[2].parse::<i64>() {
+        time
+    } else {
+        bad_args!(l, "report time must be in milliseconds since the epoch");
+    };
+    let dt = Utc
+        .timestamp_millis_opt(time)
+        .single()
+        .ok_or_else(|| anyhow!("invalid report time {}", time))?;
+
+    let (script, start, end, duration, status, outcome, ru, output, trunc) =
+        match target(a.opts().opt_str("d"))? {
+            Target::Api(c) => {
+                let r = c
+                    .report_get()
+                    .host(host)
+                    .job(job)
+                    .time(time)
+                    .send()
+                    .await?
+                    .into_inner();
+
+                (
+                    r.script,
+                    r.start_time,
+                    r.end_time,
+                    r.duration_millis,
+                    r.termination
+                        .map(|t| match t {
+                            types::Termination::Exited { code } => {
+                                code.to_string()
+                            }
+                            types::Termination::Signalled { signal } => {
+                                format!("signal {}", signal)
+                            }
+                            types::Termination::CoreDumped { signal } => {
+                                format!("signal {}, core dumped", signal)
+                            }
+                            types::Termination::WaitError { message } => {
+                                format!("wait error: {}", message)
+                            }
+                        })
+                        .or_else(|| r.exit_status.map(|s| s.to_string())),
+                    r.outcome.and_then(|o| match o {
+                        types::ReportOutcome::Completed => None,
+                        types::ReportOutcome::TimedOut => Some("timed out"),
+                        types::ReportOutcome::Skipped => Some("skipped"),
+                        types::ReportOutcome::Abandoned => Some("abandoned"),
+                    }),
+                    r.rusage.map(|u| {
+                        (u.user_cpu_millis, u.system_cpu_millis, u.max_rss_kib)
+                    }),
+                    r.output
+                        .into_iter()
+                        .map(|o| (o.time, o.stream, o.msg))
+                        .collect::<Vec<_>>(),
+                    r.truncation
+                        .map(|t| (t.after, t.omitted_lines, t.omitted_bytes)),
+                )
+            }
+            Target::Dir(_, reports) => {
+                let f = reports
+                    .load(host, job, &dt)?
+                    .ok_or_else(|| anyhow!("report not found"))?;
+
+                (
+                    f.script,
+                    f.time_start,
+                    f.time_end,
+                    f.duration,
+                    f.termination
+                        .map(|t| match t {
+                            Termination::Exited { code } => code.to_string(),
+                            Termination::Signalled { signal } => {
+                                format!("signal {}", signal)
+                            }
+                            Termination::CoreDumped { signal } => {
+                                format!("signal {}, core dumped", signal)
+                            }
+                            Termination::WaitError { message } => {
+                                format!("wait error: {}", message)
+                            }
+                        })
+                        .or_else(|| f.status.map(|s| s.to_string())),
+                    f.outcome.and_then(|o| match o {
+                        ReportOutcome::Completed => None,
+                        ReportOutcome::TimedOut => Some("timed out"),
+                        ReportOutcome::Skipped => Some("skipped"),
+                        ReportOutcome::Abandoned => Some("abandoned"),
+                    }),
+                    f.rusage.as_ref().map(|u| {
+                        (u.user_cpu_millis, u.system_cpu_millis, u.max_rss_kib)
+                    }),
+                    f.output_records()
+                        .map(|o| (o.time, o.stream.clone(), o.msg.clone()))
+                        .collect::<Vec<_>>(),
+                    f.truncation
+                        .as_ref()
+                        .map(|t| (t.after, t.omitted_lines, t.omitted_bytes)),
+                )
+            }
+        };
+
+    println!("host:     {}", host);
+    println!("job:      {}", job);
+    println!("time:     {}", fmt_time(&dt));
+    println!("script:   {}", script);
+    println!("start:    {}", fmt_time(&start));
+    if let Some(end) = end {
+        println!("end:      {}", fmt_time(&end));
+    }
+    if let Some(duration) = duration {
+        println!("duration: {}ms", duration);
+    }
+    match (status, outcome) {
+        (Some(status), Some(outcome)) => {
+            println!("status:   {} ({})", status, outcome);
+        }
+        (Some(status), None) => println!("status:   {}", status),
+        (None, Some(outcome)) => println!("status:   - ({})", outcome),
+        (None, None) => println!("status:   still running"),
+    }
+    if let Some((user, system, rss)) = ru {
+        println!("cpu:      {}ms user, {}ms system", user, system);
+        println!("max rss:  {} KiB", rss);
+    }
+
+    println!();
+    for (i, (time, stream, msg)) in output.iter().enumerate() {
+        if let Some((after, lines, bytes)) = trunc {
+            if i as u64 == after {
+                println!("... {} lines ({} bytes) omitted ...", lines, bytes);
+            }
+        }
+        println!("{} {:<6} {}", fmt_time(time), stream, msg);
+    }
+
+    Ok(())
+}
+
+async fn cmd_prune(mut l: Level<()>) -> Result<()> {
+    l.usage_args(Some("DAYS"));
+    l.optopt("H", "", "only prune reports for this host", "HOST");
+    l.optopt("j", "", "only prune reports for this job", "JOB");
+    opt_dir(&mut l);
+
+    let a = args!(l);
+    if a.args().len() != 1 {
+        bad_args!(l, "specify the number of days of history to keep");
+    }
+    let days = if let Ok(days) = a.args()[0].parse::<u32>() {
+        days
+    } else {
+        bad_args!(l, "number of days must be a positive integer");
+    };
+    let before = Utc::now() - chrono::Duration::days(days.into());
+    let host = a.opts().opt_str("H");
+    let job = a.opts().opt_str("j");
+
+    let removed = match target(a.opts().opt_str("d"))? {
+        Target::Api(c) => {
+            c.admin_prune()
+                .body(types::PruneBody { before, host, job })
+                .send()
+                .await?
+                .removed
+        }
+        Target::Dir(_, reports) => {
+            reports.prune(&before, host.as_deref(), job.as_deref())? as u64
+        }
+    };
+
+    println!(
+        "removed {} reports from before {}",
+        removed,
+        fmt_time(&before)
+    );
+    Ok(())
+}
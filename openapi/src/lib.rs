@@ -1,6 +1,7 @@
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 
 pub mod gen {
@@ -15,6 +16,8 @@ pub use gen::{types, Client, Error};
 pub struct ClientBuilder {
     url: String,
     token: Option<String>,
+    ca_file: Option<PathBuf>,
+    identity: Option<(PathBuf, PathBuf)>,
 }
 
 impl ClientBuilder {
@@ -22,6 +25,8 @@ impl ClientBuilder {
         ClientBuilder {
             url: url.to_string(),
             token: None,
+            ca_file: None,
+            identity: None,
         }
     }
 
@@ -30,6 +35,33 @@ impl ClientBuilder {
         self
     }
 
+    /**
+     * Trust the certificates in this PEM bundle, in addition to the system
+     * roots, when verifying the server certificate.  This allows the use of a
+     * server with a self-signed certificate.
+     */
+    pub fn ca_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.ca_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /**
+     * Present a client certificate, rather than a bearer token, to identify
+     * this host.  Both files must be in PEM format, and the key must be a
+     * PKCS#8 private key.
+     */
+    pub fn client_cert<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cert_file: P,
+        key_file: Q,
+    ) -> &mut Self {
+        self.identity = Some((
+            cert_file.as_ref().to_path_buf(),
+            key_file.as_ref().to_path_buf(),
+        ));
+        self
+    }
+
     pub fn build(&mut self) -> Result<Client> {
         let mut dh = HeaderMap::new();
 
@@ -40,12 +72,34 @@ impl ClientBuilder {
             );
         }
 
-        let client = reqwest::ClientBuilder::new()
+        let mut client = reqwest::ClientBuilder::new()
             .timeout(Duration::from_secs(15))
             .tcp_keepalive(Duration::from_secs(30))
             .connect_timeout(Duration::from_secs(10))
-            .default_headers(dh)
-            .build()?;
+            .default_headers(dh);
+
+        if let Some(ca_file) = self.ca_file.as_deref() {
+            let pem = std::fs::read(ca_file)
+                .with_context(|| format!("reading {}", ca_file.display()))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("parsing {}", ca_file.display()))?
+            {
+                client = client.add_root_certificate(cert);
+            }
+        }
+
+        if let Some((cert_file, key_file)) = &self.identity {
+            let cert = std::fs::read(cert_file)
+                .with_context(|| format!("reading {}", cert_file.display()))?;
+            let key = std::fs::read(key_file)
+                .with_context(|| format!("reading {}", key_file.display()))?;
+            client = client.identity(
+                reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                    .context("loading client certificate")?,
+            );
+        }
+
+        let client = client.build()?;
 
         Ok(Client::new_with_client(&self.url, client))
     }
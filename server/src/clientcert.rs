@@ -0,0 +1,226 @@
+/*
+ * Client certificate authentication.  Dropshot neither requests client
+ * certificates on its own TLS listener nor exposes the peer certificate to
+ * request handlers, so when client certificates are configured we terminate
+ * TLS ourselves.  The dropshot server then listens only on the loopback
+ * interface, and each connection we accept is passed through to it over a
+ * connection of our own.  If the client presented a certificate that our CA
+ * has signed, we record the host to which it belongs against the local
+ * address of that inner connection, which is the remote address dropshot
+ * reports for each request that arrives on it.
+ */
+
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+#[allow(unused_imports)]
+use slog::{debug, error, info, o, warn, Logger};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{ClientCertConfig, TlsConfig};
+
+/**
+ * How long a client may take to complete the TLS handshake.
+ */
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct ClientCerts {
+    log: Logger,
+    tls: TlsConfig,
+    cc: ClientCertConfig,
+    acceptor: Mutex<TlsAcceptor>,
+    /*
+     * Maps the local address of each inner connection to the dropshot server
+     * to the host that presented a certificate on the outer connection.
+     */
+    peers: Mutex<HashMap<SocketAddr, String>>,
+}
+
+fn load_certs(p: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let f = std::fs::File::open(p)
+        .with_context(|| format!("opening {}", p.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(f))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("reading {}", p.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", p.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(p: &Path) -> Result<PrivateKeyDer<'static>> {
+    let f = std::fs::File::open(p)
+        .with_context(|| format!("opening {}", p.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(f))
+        .with_context(|| format!("reading {}", p.display()))?
+        .ok_or_else(|| anyhow!("no private key found in {}", p.display()))
+}
+
+fn load_acceptor(
+    tls: &TlsConfig,
+    cc: &ClientCertConfig,
+) -> Result<TlsAcceptor> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&cc.ca_file)? {
+        roots.add(cert).context("loading client CA certificate")?;
+    }
+
+    /*
+     * Hosts that authenticate with a key need not present a certificate at
+     * all, but any certificate that is presented must be one our CA signed.
+     */
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()
+        .context("client certificate verifier")?;
+
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certs(&tls.cert_file)?, load_key(&tls.key_file)?)
+        .context("TLS configuration")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+impl ClientCerts {
+    pub fn new(
+        log: Logger,
+        tls: TlsConfig,
+        cc: ClientCertConfig,
+    ) -> Result<Arc<ClientCerts>> {
+        let acceptor = Mutex::new(load_acceptor(&tls, &cc)?);
+
+        Ok(Arc::new(ClientCerts {
+            log,
+            tls,
+            cc,
+            acceptor,
+            peers: Default::default(),
+        }))
+    }
+
+    /**
+     * Load the certificate, key, and client CA from disk again.  Connections
+     * that are already established are not affected.
+     */
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = load_acceptor(&self.tls, &self.cc)?;
+        *self.acceptor.lock().unwrap() = acceptor;
+        Ok(())
+    }
+
+    /**
+     * Which host, if any, presented a verified certificate on the connection
+     * that dropshot sees as coming from this address?
+     */
+    pub fn host_for(&self, remote: &SocketAddr) -> Option<String> {
+        self.peers.lock().unwrap().get(remote).cloned()
+    }
+
+    /**
+     * Accept connections from clients, passing each one through to the
+     * dropshot server at "inner".
+     */
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        inner: SocketAddr,
+    ) {
+        loop {
+            let (sock, peer) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    /*
+                     * Errors here are generally transient, like running out
+                     * of file descriptors, so we pause and try again.
+                     */
+                    error!(self.log, "accept failure: {:?}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let cc = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = cc.connection(sock, peer, inner).await {
+                    warn!(cc.log, "connection from {}: {:?}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn connection(
+        &self,
+        sock: TcpStream,
+        peer: SocketAddr,
+        inner: SocketAddr,
+    ) -> Result<()> {
+        let acceptor = self.acceptor.lock().unwrap().clone();
+        let mut tls =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(sock))
+                .await
+                .map_err(|_| anyhow!("TLS handshake timed out"))?
+                .context("TLS handshake")?;
+        let host = self.verified_host(tls.get_ref().1.peer_certificates());
+
+        let mut upstream = TcpStream::connect(inner)
+            .await
+            .context("connecting to server")?;
+        let local = upstream.local_addr()?;
+        debug!(
+            self.log,
+            "connection from {} via {} (host {:?})", peer, local, host
+        );
+
+        if let Some(host) = host {
+            self.peers.lock().unwrap().insert(local, host);
+        }
+        let res = tokio::io::copy_bidirectional(&mut tls, &mut upstream).await;
+        self.peers.lock().unwrap().remove(&local);
+
+        res?;
+        Ok(())
+    }
+
+    /**
+     * By the time the handshake is complete, rustls has checked that any
+     * certificate the client presented was signed by our CA.  The host is
+     * determined by the common name in the subject of the client's own
+     * certificate, which comes first in the chain.
+     */
+    fn verified_host(
+        &self,
+        certs: Option<&[CertificateDer]>,
+    ) -> Option<String> {
+        let der = certs?.first()?;
+
+        let cn = match x509_parser::parse_x509_certificate(der) {
+            Ok((_, cert)) => cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_string),
+            Err(e) => {
+                warn!(self.log, "could not parse client certificate: {:?}", e);
+                None
+            }
+        }?;
+
+        if let Some(host) = self.cc.hosts.get(&cn) {
+            Some(host.to_string())
+        } else {
+            warn!(self.log, "no host for certificate subject {:?}", cn);
+            None
+        }
+    }
+}
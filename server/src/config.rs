@@ -0,0 +1,275 @@
+/*
+ * The server configuration file, in TOML format.  For example:
+ *
+ *     bind_address = "0.0.0.0:9978"
+ *     data_dir = "/var/keeper"
+ *
+ *     [log]
+ *     mode = "file"
+ *     level = "info"
+ *     path = "/var/log/keeper.log"
+ *     if_exists = "append"
+ *
+ *     [tls]
+ *     cert_file = "/etc/keeper/cert.pem"
+ *     key_file = "/etc/keeper/key.pem"
+ *
+ *     [client_certs]
+ *     ca_file = "/etc/keeper/client-ca.pem"
+ *     hosts = { "web01.example.com" = "web01" }
+ *
+ *     [retention]
+ *     days = 90
+ *
+ *     [heartbeat]
+ *     abandon_seconds = 900
+ *
+ *     [limits]
+ *     request_body_max_bytes = 1048576
+ *
+ *     [[notify.webhooks]]
+ *     url = "https://hooks.example.com/keeper"
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use dropshot::{ConfigLogging, ConfigLoggingLevel};
+use keeper_store::{name_ok, OutputLimits};
+use serde::Deserialize;
+
+use crate::notify::NotifyConfig;
+
+fn default_bind_address() -> SocketAddr {
+    "0.0.0.0:9978".parse().unwrap()
+}
+
+fn default_log() -> ConfigLogging {
+    ConfigLogging::StderrTerminal {
+        level: ConfigLoggingLevel::Info,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_bind_address")]
+    pub bind_address: SocketAddr,
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    #[serde(default = "default_log")]
+    pub log: ConfigLogging,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub client_certs: Option<ClientCertConfig>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: default_bind_address(),
+            data_dir: None,
+            log: default_log(),
+            tls: None,
+            client_certs: None,
+            retention: Default::default(),
+            heartbeat: Default::default(),
+            limits: Default::default(),
+            notify: Default::default(),
+        }
+    }
+}
+
+/**
+ * If specified, the server will serve HTTPS rather than HTTP.  The
+ * certificate and key (and the client CA, if configured) are loaded again
+ * from these paths when the server receives SIGHUP.
+ */
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+/**
+ * Hosts may authenticate with a client certificate rather than a key.  This
+ * requires TLS, and the server will then request a certificate from each
+ * client and verify it against the CA certificates in "ca_file".  Clients
+ * that do not present one may still use a key.
+ *
+ * The common name in the subject of a verified certificate must be listed in
+ * "hosts", which maps it to the host name it identifies.  Revoking a host
+ * ends its certificate access as well, until it is approved again.  Hosts
+ * authenticated this way are never granted administrative privileges.
+ */
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertConfig {
+    pub ca_file: PathBuf,
+    pub hosts: HashMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    /**
+     * If specified, reports older than this many days are removed
+     * periodically.
+     */
+    #[serde(default)]
+    pub days: Option<u32>,
+}
+
+fn default_abandon_seconds() -> u64 {
+    900
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /**
+     * A running report from which we have heard nothing for this long is
+     * sealed and marked as abandoned.  Clients send a heartbeat every minute
+     * while the job runs, and once a client has sent one, output counts as
+     * well.  Older clients send no heartbeats, so their reports are never
+     * abandoned.  Set this to zero to disable the check.
+     */
+    #[serde(default = "default_abandon_seconds")]
+    pub abandon_seconds: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            abandon_seconds: default_abandon_seconds(),
+        }
+    }
+}
+
+fn default_request_body_max_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_output_max_lines() -> usize {
+    20_000
+}
+
+fn default_output_max_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    #[serde(default = "default_request_body_max_bytes")]
+    pub request_body_max_bytes: usize,
+    /**
+     * Limits on the output stored for each report.  Once a report exceeds
+     * either limit, we keep only the start and the end of the output.
+     */
+    #[serde(default = "default_output_max_lines")]
+    pub output_max_lines: usize,
+    #[serde(default = "default_output_max_bytes")]
+    pub output_max_bytes: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            request_body_max_bytes: default_request_body_max_bytes(),
+            output_max_lines: default_output_max_lines(),
+            output_max_bytes: default_output_max_bytes(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn output(&self) -> OutputLimits {
+        OutputLimits {
+            max_lines: self.output_max_lines,
+            max_bytes: self.output_max_bytes,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(p: &Path) -> Result<Config> {
+        let s = std::fs::read_to_string(p)
+            .with_context(|| format!("reading {}", p.display()))?;
+        toml::from_str(&s).with_context(|| format!("parsing {}", p.display()))
+    }
+
+    /**
+     * Check everything we can about the configuration before starting, so
+     * that mistakes are reported immediately rather than when some rarely
+     * used facility is first needed.
+     */
+    pub fn check(&self) -> Result<()> {
+        if let Some(dir) = &self.data_dir {
+            if !dir.is_dir() {
+                bail!("data directory {} should be a directory", dir.display());
+            }
+        } else {
+            bail!("must specify data directory");
+        }
+
+        if let Some(tls) = &self.tls {
+            for f in [&tls.cert_file, &tls.key_file] {
+                if !f.is_file() {
+                    bail!("TLS file {} does not exist", f.display());
+                }
+            }
+        }
+
+        if let Some(cc) = &self.client_certs {
+            if self.tls.is_none() {
+                bail!("client certificates require TLS");
+            }
+            if !cc.ca_file.is_file() {
+                bail!("TLS file {} does not exist", cc.ca_file.display());
+            }
+            if cc.hosts.is_empty() {
+                bail!("client certificates require at least one host");
+            }
+            for host in cc.hosts.values() {
+                if !name_ok(host) {
+                    bail!("invalid host name {:?} for certificate", host);
+                }
+            }
+        }
+
+        if self.retention.days == Some(0) {
+            bail!("retention period must be at least one day");
+        }
+
+        if self.heartbeat.abandon_seconds != 0
+            && self.heartbeat.abandon_seconds < 120
+        {
+            bail!("abandonment window must be at least two minutes");
+        }
+
+        if self.limits.request_body_max_bytes == 0 {
+            bail!("request body size limit must not be zero");
+        }
+        if self.limits.output_max_lines < 2 || self.limits.output_max_bytes < 2
+        {
+            bail!("output limits are too small");
+        }
+
+        self.notify.check().context("notification configuration")?;
+
+        Ok(())
+    }
+}
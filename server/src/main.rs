@@ -8,22 +8,37 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 #[allow(unused_imports)]
 use slog::{debug, error, info, o, warn, Logger};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::result::Result as SResult;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::RwLock;
 
 use dropshot::{
-    endpoint, ApiDescription, Body, ConfigDropshot, ConfigLogging,
-    ConfigLoggingLevel, HttpError, HttpResponseCreated, HttpServerStarter,
-    RequestContext, RequestInfo, TypedBody,
+    endpoint, ApiDescription, Body, ConfigDropshot, ConfigTls, HttpError,
+    HttpResponseCreated, HttpResponseDeleted, HttpResponseOk,
+    HttpResponseUpdatedNoContent, HttpServerStarter, PaginationParams, Path,
+    Query, RequestContext, RequestInfo, ResultsPage, TypedBody, WhichPage,
 };
 use hyper::{header::AUTHORIZATION, StatusCode};
 
-mod store;
-use store::*;
+use keeper_store::*;
+
+mod clientcert;
+use clientcert::ClientCerts;
+
+mod config;
+use config::Config;
+
+mod notify;
+use notify::Notifier;
+
 mod prometheus;
 use prometheus::*;
 
+mod smtp;
+
 trait MakeInternalError<T> {
     fn or_500(self) -> SResult<T, HttpError>;
 }
@@ -47,10 +62,12 @@ impl<T> MakeInternalError<T> for std::result::Result<T, anyhow::Error> {
 }
 
 struct App {
-    #[allow(dead_code)]
     log: Logger,
     keys: RwLock<KeyStore>,
-    reports: RwLock<ReportStore>,
+    reports: Arc<RwLock<ReportStore>>,
+    notify: Arc<Notifier>,
+    client_certs: Option<Arc<ClientCerts>>,
+    output_limits: OutputLimits,
 }
 
 impl App {
@@ -68,7 +85,7 @@ impl App {
             None
         };
 
-        if let Some(v) = v {
+        if let Some(v) = &v {
             let t = v.split_whitespace().map(|s| s.trim()).collect::<Vec<_>>();
 
             if t.len() == 2 && t.iter().all(|s| !s.is_empty()) {
@@ -87,12 +104,66 @@ impl App {
             }
         }
 
+        if v.is_none() {
+            if let Some(auth) = self.client_cert_auth(req).await? {
+                return Ok(auth);
+            }
+        }
+
         Err(HttpError::for_client_error(
             None,
             StatusCode::UNAUTHORIZED,
             "invalid Authorization header".into(),
         ))
     }
+
+    /**
+     * Requests without an Authorization header may instead arrive on a
+     * connection where the client presented a verified certificate.
+     */
+    async fn client_cert_auth(
+        &self,
+        req: &RequestInfo,
+    ) -> SResult<Option<Auth>, HttpError> {
+        let cc = if let Some(cc) = &self.client_certs {
+            cc
+        } else {
+            return Ok(None);
+        };
+
+        let host = if let Some(host) = cc.host_for(&req.remote_addr()) {
+            host
+        } else {
+            return Ok(None);
+        };
+
+        let keys = self.keys.read().await;
+        let auth = keys.host_auth(&host, "certificate").or_500()?;
+        if auth.is_none() {
+            warn!(
+                self.log,
+                "certificate for host {} that has been revoked", host
+            );
+        }
+        Ok(auth)
+    }
+
+    async fn require_admin(
+        &self,
+        req: &RequestInfo,
+    ) -> SResult<Auth, HttpError> {
+        let auth = self.require_auth(req).await?;
+
+        if !auth.admin {
+            return Err(HttpError::for_client_error(
+                None,
+                StatusCode::UNAUTHORIZED,
+                "uh uh uh".into(),
+            ));
+        }
+
+        Ok(auth)
+    }
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -192,8 +263,14 @@ async fn report_start(
      */
 
     let reports = app.reports.write().await;
-    match reports.load(&body.id.host, &body.id.job, &body.id.time) {
-        Ok(Some(f)) => {
+    match reports.load_meta(&body.id.host, &body.id.job, &body.id.time) {
+        Ok(Some(mut f)) => {
+            /*
+             * A client replaying its spool after a long outage will start
+             * again with a report we may have abandoned in the meantime.
+             */
+            reopen(&reports, &body.id, &mut f)?;
+
             /*
              * A report for this time exists already.  Check to make sure that
              * the report UUID is the same as what the client sent; if it is, we
@@ -232,6 +309,12 @@ async fn report_start(
                 duration: None,
                 status: None,
                 output: Vec::new(),
+                output_tail: Vec::new(),
+                truncation: None,
+                outcome: None,
+                last_heartbeat: None,
+                rusage: None,
+                termination: None,
                 script: body.script,
             };
             if let Err(e) =
@@ -254,6 +337,51 @@ async fn report_start(
     }
 }
 
+/**
+ * If a report was abandoned, but the client that started it has since been in
+ * touch again, reopen the report.
+ */
+fn reopen(
+    reports: &ReportStore,
+    id: &ReportId,
+    f: &mut PostFile,
+) -> SResult<(), HttpError> {
+    if id.uuid == f.report_uuid && f.reopen() {
+        reports.store(&id.host, &id.job, &id.time, f).or_500()?;
+    }
+    Ok(())
+}
+
+/**
+ * Output from a running job shows that the client is still there, just as a
+ * heartbeat does.  So that we are not rewriting the report for every batch,
+ * we only record this once the last heartbeat is somewhat out of date.  Older
+ * clients send output but no heartbeats, and their reports must not become
+ * eligible for abandonment, so we only do this once a heartbeat has arrived.
+ */
+fn touch(
+    reports: &ReportStore,
+    id: &ReportId,
+    f: &mut PostFile,
+) -> SResult<(), HttpError> {
+    if id.uuid != f.report_uuid || f.sealed {
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    if let Some(hb) = f.last_heartbeat {
+        if now.signed_duration_since(hb) < chrono::Duration::seconds(30) {
+            return Ok(());
+        }
+    } else {
+        return Ok(());
+    }
+
+    f.last_heartbeat = Some(now);
+    reports.store(&id.host, &id.job, &id.time, f).or_500()?;
+    Ok(())
+}
+
 #[derive(Deserialize, JsonSchema)]
 struct ReportOutputBody {
     id: ReportId,
@@ -293,8 +421,11 @@ async fn report_output(
      */
 
     let reports = app.reports.write().await;
-    match reports.load(&body.id.host, &body.id.job, &body.id.time) {
+    match reports.load_meta(&body.id.host, &body.id.job, &body.id.time) {
         Ok(Some(mut f)) => {
+            reopen(&reports, &body.id, &mut f)?;
+            touch(&reports, &body.id, &mut f)?;
+
             /*
              * A report for this time exists already.  Check to make sure that
              * the report UUID is the same as what the client sent; if it is, we
@@ -315,31 +446,23 @@ async fn report_output(
             } else {
                 /*
                  * This job exists and the UUID matches the one recorded when
-                 * the record was created.  Check to make sure the output
-                 * record does not already appear in the file.
+                 * the record was created.  The store will check to make sure
+                 * the output record has not already been appended.
                  */
-                if f.output.contains(&body.record) {
-                    Ok(HttpResponseCreated(ReportResult {
-                        existed_already: true,
-                    }))
-                } else {
-                    f.output.push(body.record);
-
-                    if let Err(e) = reports.store(
-                        &body.id.host,
-                        &body.id.job,
-                        &body.id.time,
-                        &f,
-                    ) {
-                        Err(HttpError::for_internal_error(format!(
-                            "store file? {:?}",
-                            e
-                        )))
-                    } else {
-                        Ok(HttpResponseCreated(ReportResult {
-                            existed_already: false,
-                        }))
-                    }
+                match reports.append_output(
+                    &body.id.host,
+                    &body.id.job,
+                    &body.id.time,
+                    &body.record,
+                    &app.output_limits,
+                ) {
+                    Ok(appended) => Ok(HttpResponseCreated(ReportResult {
+                        existed_already: !appended,
+                    })),
+                    Err(e) => Err(HttpError::for_internal_error(format!(
+                        "append output? {:?}",
+                        e
+                    ))),
                 }
             }
         }
@@ -361,6 +484,201 @@ async fn report_output(
     }
 }
 
+#[derive(Deserialize, JsonSchema)]
+struct ReportOutputBatchBody {
+    id: ReportId,
+    /**
+     * Each record must have a sequence number, so that a batch sent again
+     * after a failure is not stored twice.
+     */
+    records: Vec<OutputRecord>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ReportOutputBatchResult {
+    appended: u64,
+    duplicates: u64,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/report/output/batch",
+}]
+async fn report_output_batch(
+    arc: RequestContext<App>,
+    body: TypedBody<ReportOutputBatchBody>,
+) -> SResult<HttpResponseCreated<ReportOutputBatchResult>, HttpError> {
+    let app = arc.context();
+    let body = body.into_inner();
+
+    let auth = app.require_auth(&arc.request).await?;
+    if body.id.host != auth.host {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::UNAUTHORIZED,
+            "uh uh uh".into(),
+        ));
+    }
+
+    if !name_ok(&body.id.job) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "job name too short".into(),
+        ));
+    }
+
+    if body.records.iter().any(|r| r.seq.is_none()) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "batched records must have a sequence number".into(),
+        ));
+    }
+
+    let reports = app.reports.write().await;
+    let mut f =
+        match reports.load_meta(&body.id.host, &body.id.job, &body.id.time) {
+            Ok(Some(f)) => f,
+            Ok(None) => {
+                return Err(HttpError::for_client_error(
+                    None,
+                    StatusCode::BAD_REQUEST,
+                    "this job does not exist".into(),
+                ));
+            }
+            Err(e) => {
+                error!(arc.log, "load file error: {:?}", e);
+                return Err(HttpError::for_internal_error(
+                    "data store error".into(),
+                ));
+            }
+        };
+
+    reopen(&reports, &body.id, &mut f)?;
+    touch(&reports, &body.id, &mut f)?;
+
+    if body.id.uuid != f.report_uuid {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::CONFLICT,
+            "this time already submitted, with different UUID".into(),
+        ));
+    } else if f.sealed {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::CONFLICT,
+            "this job is already complete".into(),
+        ));
+    }
+
+    let mut res = ReportOutputBatchResult {
+        appended: 0,
+        duplicates: 0,
+    };
+    for r in body.records.iter() {
+        match reports.append_output(
+            &body.id.host,
+            &body.id.job,
+            &body.id.time,
+            r,
+            &app.output_limits,
+        ) {
+            Ok(true) => res.appended += 1,
+            Ok(false) => res.duplicates += 1,
+            Err(e) => {
+                return Err(HttpError::for_internal_error(format!(
+                    "append output? {:?}",
+                    e
+                )));
+            }
+        }
+    }
+
+    Ok(HttpResponseCreated(res))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ReportHeartbeatBody {
+    id: ReportId,
+}
+
+/*
+ * Clients call this periodically while a job is running, so that we can tell
+ * the difference between a job that is still running and one whose client
+ * has gone away.
+ */
+#[endpoint {
+    method = POST,
+    path = "/report/heartbeat",
+}]
+async fn report_heartbeat(
+    arc: RequestContext<App>,
+    body: TypedBody<ReportHeartbeatBody>,
+) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    let app = arc.context();
+    let body = body.into_inner();
+
+    let auth = app.require_auth(&arc.request).await?;
+    if body.id.host != auth.host {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::UNAUTHORIZED,
+            "uh uh uh".into(),
+        ));
+    }
+
+    if !name_ok(&body.id.job) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "job name too short".into(),
+        ));
+    }
+
+    let reports = app.reports.write().await;
+    let mut f =
+        match reports.load_meta(&body.id.host, &body.id.job, &body.id.time) {
+            Ok(Some(f)) => f,
+            Ok(None) => {
+                return Err(HttpError::for_client_error(
+                    None,
+                    StatusCode::BAD_REQUEST,
+                    "this job does not exist".into(),
+                ));
+            }
+            Err(e) => {
+                error!(arc.log, "load file error: {:?}", e);
+                return Err(HttpError::for_internal_error(
+                    "data store error".into(),
+                ));
+            }
+        };
+
+    if body.id.uuid != f.report_uuid {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::CONFLICT,
+            "this time already submitted, with different UUID".into(),
+        ));
+    }
+    f.reopen();
+    if f.sealed {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::CONFLICT,
+            "this job is already complete".into(),
+        ));
+    }
+
+    f.last_heartbeat = Some(Utc::now());
+    reports
+        .store(&body.id.host, &body.id.job, &body.id.time, &f)
+        .or_500()?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
 #[derive(Deserialize, JsonSchema)]
 struct ReportFinishBody {
     id: ReportId,
@@ -368,6 +686,16 @@ struct ReportFinishBody {
     end_time: DateTime<Utc>,
     duration_millis: u64,
     exit_status: i32,
+    /**
+     * Older clients do not send an outcome, but could only report jobs that
+     * completed.
+     */
+    #[serde(default)]
+    outcome: ReportOutcome,
+    #[serde(default)]
+    rusage: Option<ResourceUsage>,
+    #[serde(default)]
+    termination: Option<Termination>,
 }
 
 #[endpoint {
@@ -402,8 +730,10 @@ async fn report_finish(
      * XXX check that job time is in the last fornight, or whatever
      */
     let reports = app.reports.write().await;
-    match reports.load(&body.id.host, &body.id.job, &body.id.time) {
+    match reports.load_meta(&body.id.host, &body.id.job, &body.id.time) {
         Ok(Some(mut f)) => {
+            reopen(&reports, &body.id, &mut f)?;
+
             /*
              * A report for this time exists already.  Check to make sure that
              * the report UUID is the same as what the client sent; if it is, we
@@ -423,8 +753,27 @@ async fn report_finish(
                 f.duration = Some(body.duration_millis);
                 f.time_end = Some(body.end_time);
                 f.status = Some(body.exit_status);
+                f.outcome = Some(body.outcome);
+                f.rusage = body.rusage;
+                f.termination = body.termination;
                 f.sealed = true;
 
+                match reports.finish_output(
+                    &body.id.host,
+                    &body.id.job,
+                    &body.id.time,
+                    &app.output_limits,
+                ) {
+                    Ok(Some(t)) => f.truncation = Some(t),
+                    Ok(None) => (),
+                    Err(e) => {
+                        return Err(HttpError::for_internal_error(format!(
+                            "finish output? {:?}",
+                            e
+                        )));
+                    }
+                }
+
                 if let Err(e) = reports.store(
                     &body.id.host,
                     &body.id.job,
@@ -436,6 +785,27 @@ async fn report_finish(
                         e
                     )))
                 } else {
+                    /*
+                     * Notifications include the end of the output, so we
+                     * must load the complete report.
+                     */
+                    match reports.load(
+                        &body.id.host,
+                        &body.id.job,
+                        &body.id.time,
+                    ) {
+                        Ok(Some(f)) => app.notify.report(
+                            &body.id.host,
+                            &body.id.job,
+                            &body.id.time,
+                            &f,
+                        ),
+                        Ok(None) => (),
+                        Err(e) => {
+                            error!(arc.log, "load for notify: {:?}", e);
+                        }
+                    }
+
                     Ok(HttpResponseCreated(ReportResult {
                         existed_already: false,
                     }))
@@ -460,45 +830,158 @@ async fn report_finish(
     }
 }
 
+#[derive(Deserialize, JsonSchema)]
+struct ReportPath {
+    host: String,
+    job: String,
+    /**
+     * The report time, as milliseconds since the UNIX epoch.
+     */
+    time: i64,
+}
+
 #[derive(Serialize, JsonSchema)]
-struct PingResult {
-    ok: bool,
+struct Report {
     host: String,
+    job: String,
+    time: DateTime<Utc>,
+    uuid: String,
+    pid: u32,
+    script: String,
+    start_time: DateTime<Utc>,
+    end_time: Option<DateTime<Utc>>,
+    duration_millis: Option<u64>,
+    exit_status: Option<i32>,
+    sealed: bool,
+    output: Vec<OutputRecord>,
+    /**
+     * If the output of this job exceeded the limits configured on the server,
+     * some of it was discarded.
+     */
+    truncation: Option<OutputTruncation>,
+    outcome: Option<ReportOutcome>,
+    rusage: Option<ResourceUsage>,
+    termination: Option<Termination>,
 }
 
 #[endpoint {
     method = GET,
-    path = "/ping",
+    path = "/reports/{host}/{job}/{time}",
 }]
-async fn ping(
+async fn report_get(
     arc: RequestContext<App>,
-) -> SResult<HttpResponseCreated<PingResult>, HttpError> {
+    path: Path<ReportPath>,
+) -> SResult<HttpResponseOk<Report>, HttpError> {
     let app = arc.context();
+    let path = path.into_inner();
 
+    /*
+     * Hosts may read back their own reports, but only a global view
+     * credential may read the reports of another host.
+     */
     let auth = app.require_auth(&arc.request).await?;
+    if path.host != auth.host && !auth.global_view {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::UNAUTHORIZED,
+            "uh uh uh".into(),
+        ));
+    }
 
-    Ok(HttpResponseCreated(PingResult {
-        ok: true,
-        host: auth.host,
-    }))
+    if !name_ok(&path.host) || !name_ok(&path.job) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid host or job name".into(),
+        ));
+    }
+
+    let time = if let Some(time) = Utc.timestamp_millis_opt(path.time).single()
+    {
+        time
+    } else {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid report time".into(),
+        ));
+    };
+
+    let reports = app.reports.read().await;
+    match reports.load(&path.host, &path.job, &time) {
+        Ok(Some(f)) => Ok(HttpResponseOk(Report {
+            output: f.output_records().cloned().collect(),
+            host: path.host,
+            job: path.job,
+            time,
+            uuid: f.report_uuid,
+            pid: f.report_pid,
+            script: f.script,
+            start_time: f.time_start,
+            end_time: f.time_end,
+            duration_millis: f.duration,
+            exit_status: f.status,
+            sealed: f.sealed,
+            truncation: f.truncation,
+            outcome: f.outcome,
+            rusage: f.rusage,
+            termination: f.termination,
+        })),
+        Ok(None) => Err(HttpError::for_not_found(
+            None,
+            "this report does not exist".into(),
+        )),
+        Err(e) => {
+            error!(arc.log, "load file error: {:?}", e);
+            Err(HttpError::for_internal_error("data store error".into()))
+        }
+    }
 }
 
-#[derive(Serialize, JsonSchema)]
-struct GlobalJobsResult {
-    summary: Vec<ReportSummary>,
+#[derive(Deserialize, JsonSchema)]
+struct JobPath {
+    host: String,
+    job: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+struct ReportHistoryScan {
+    /**
+     * Only include reports from strictly before this time.
+     */
+    before: Option<DateTime<Utc>>,
+    /**
+     * Only include reports from this time or later.
+     */
+    after: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReportHistoryPage {
+    scan: ReportHistoryScan,
+    /**
+     * The report time, in milliseconds since the UNIX epoch, of the last
+     * report on the previous page.  As report files are named for this
+     * timestamp, it serves as a stable cursor into the history.
+     */
+    last: i64,
 }
 
 #[endpoint {
     method = GET,
-    path = "/global/jobs",
+    path = "/reports/{host}/{job}",
 }]
-async fn global_jobs(
+async fn report_history(
     arc: RequestContext<App>,
-) -> SResult<HttpResponseCreated<GlobalJobsResult>, HttpError> {
+    path: Path<JobPath>,
+    query: Query<PaginationParams<ReportHistoryScan, ReportHistoryPage>>,
+) -> SResult<HttpResponseOk<ResultsPage<ReportRun>>, HttpError> {
     let app = arc.context();
+    let path = path.into_inner();
+    let query = query.into_inner();
 
     let auth = app.require_auth(&arc.request).await?;
-    if !auth.global_view {
+    if path.host != auth.host && !auth.global_view {
         return Err(HttpError::for_client_error(
             None,
             StatusCode::UNAUTHORIZED,
@@ -506,61 +989,1008 @@ async fn global_jobs(
         ));
     }
 
-    let reports = app.reports.read().await;
+    if !name_ok(&path.host) || !name_ok(&path.job) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid host or job name".into(),
+        ));
+    }
+
+    let limit = arc.page_limit(&query)?.get() as usize;
+    let (scan, before) = match &query.page {
+        WhichPage::First(scan) => (scan, scan.before),
+        WhichPage::Next(page) => {
+            let last = Utc.timestamp_millis_opt(page.last).single();
+            if last.is_none() {
+                return Err(HttpError::for_client_error(
+                    None,
+                    StatusCode::BAD_REQUEST,
+                    "invalid page token".into(),
+                ));
+            }
+            (&page.scan, last)
+        }
+    };
+
+    let reports = app.reports.read().await;
+    let runs = reports
+        .history(&path.host, &path.job, before, scan.after, limit)
+        .or_500()?;
+
+    Ok(HttpResponseOk(ResultsPage::new(runs, scan, |r, scan| {
+        ReportHistoryPage {
+            scan: scan.clone(),
+            last: r.time.timestamp_millis(),
+        }
+    })?))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct KeyRotateBody {
+    /**
+     * The new key, which must be in the same format as the key submitted
+     * during enrolment.
+     */
+    key: String,
+    /**
+     * For how long the key used to make this request should remain valid,
+     * so that jobs already running with it can finish.  Defaults to one
+     * hour, and may be at most one week.
+     */
+    grace_seconds: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct KeyRotateResult {
+    id: String,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/key/rotate",
+}]
+async fn key_rotate(
+    arc: RequestContext<App>,
+    body: TypedBody<KeyRotateBody>,
+) -> SResult<HttpResponseCreated<KeyRotateResult>, HttpError> {
+    let app = arc.context();
+    let body = body.into_inner();
+
+    let auth = app.require_auth(&arc.request).await?;
+
+    if !key_ok(&body.key) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid key format".into(),
+        ));
+    }
+
+    let grace = body.grace_seconds.unwrap_or(3600).min(7 * 86400);
+    let grace = chrono::Duration::seconds(grace as i64);
+
+    let keys = app.keys.write().await;
+    let id = keys
+        .rotate_key(&auth.host, &auth.key_id, &body.key, grace)
+        .map_err(|e| {
+            warn!(arc.log, "key rotation for {} failed: {:?}", auth.host, e);
+            HttpError::for_client_error(
+                None,
+                StatusCode::BAD_REQUEST,
+                "key rotation failed".into(),
+            )
+        })?;
+
+    Ok(HttpResponseCreated(KeyRotateResult { id }))
+}
+
+#[derive(Serialize, JsonSchema)]
+struct PingResult {
+    ok: bool,
+    host: String,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/ping",
+}]
+async fn ping(
+    arc: RequestContext<App>,
+) -> SResult<HttpResponseCreated<PingResult>, HttpError> {
+    let app = arc.context();
+
+    let auth = app.require_auth(&arc.request).await?;
+
+    Ok(HttpResponseCreated(PingResult {
+        ok: true,
+        host: auth.host,
+    }))
+}
+
+#[derive(Serialize, JsonSchema)]
+struct GlobalJobsResult {
+    summary: Vec<ReportSummary>,
+    schedules: Vec<ScheduleStatus>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/global/jobs",
+}]
+async fn global_jobs(
+    arc: RequestContext<App>,
+) -> SResult<HttpResponseCreated<GlobalJobsResult>, HttpError> {
+    let app = arc.context();
+
+    let auth = app.require_auth(&arc.request).await?;
+    if !auth.global_view {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::UNAUTHORIZED,
+            "uh uh uh".into(),
+        ));
+    }
+
+    let reports = app.reports.read().await;
+    let summary = reports.summary(1).or_500()?;
+    let schedules = reports.schedule_status().or_500()?;
+
+    Ok(HttpResponseCreated(GlobalJobsResult { summary, schedules }))
+}
+
+#[derive(Serialize, JsonSchema)]
+struct GlobalRunningResult {
+    running: Vec<RunningReport>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/global/running",
+}]
+async fn global_running(
+    arc: RequestContext<App>,
+) -> SResult<HttpResponseOk<GlobalRunningResult>, HttpError> {
+    let app = arc.context();
+
+    let auth = app.require_auth(&arc.request).await?;
+    if !auth.global_view {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::UNAUTHORIZED,
+            "uh uh uh".into(),
+        ));
+    }
+
+    let reports = app.reports.read().await;
+    let running = reports.running().or_500()?;
+
+    Ok(HttpResponseOk(GlobalRunningResult { running }))
+}
+
+#[endpoint {
+    method = GET,
+    path = "/global/metrics",
+    unpublished = true,
+}]
+async fn global_metrics(
+    arc: RequestContext<App>,
+) -> SResult<Response<Body>, HttpError> {
+    let app = arc.context();
+
+    let auth = app.require_auth(&arc.request).await?;
+    if !auth.global_view {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::UNAUTHORIZED,
+            "uh uh uh".into(),
+        ));
+    }
+
+    let reports = app.reports.read().await;
+
+    let mut e = Emitter::new();
+    e.define("keeper_job_age_seconds", "gauge", "age of this job report");
+    e.define(
+        "keeper_job_ok",
+        "gauge",
+        "did the last run of this job exit 0?",
+    );
+    e.define(
+        "keeper_job_duration_seconds",
+        "gauge",
+        "for how long did the last job run?",
+    );
+    e.define(
+        "keeper_job_output_truncated",
+        "gauge",
+        "was the output of the last run of this job truncated?",
+    );
+    e.define(
+        "keeper_job_timed_out",
+        "gauge",
+        "was the last run of this job killed for exceeding its timeout?",
+    );
+    e.define(
+        "keeper_job_skipped",
+        "gauge",
+        "was the latest run of this job skipped, as the previous run was \
+        not finished?",
+    );
+    e.define(
+        "keeper_job_abandoned",
+        "gauge",
+        "did we stop hearing from the client during the last run of this job?",
+    );
+    e.define(
+        "keeper_job_signal",
+        "gauge",
+        "which signal killed the last run of this job, if any?",
+    );
+    e.define(
+        "keeper_job_core_dumped",
+        "gauge",
+        "did the last run of this job dump core?",
+    );
+    e.define(
+        "keeper_job_wait_failed",
+        "gauge",
+        "was the client unable to find out how the last run of this job ended?",
+    );
+    e.define(
+        "keeper_job_cpu_user_seconds",
+        "gauge",
+        "how much user CPU time did the last run of this job use?",
+    );
+    e.define(
+        "keeper_job_cpu_system_seconds",
+        "gauge",
+        "how much system CPU time did the last run of this job use?",
+    );
+    e.define(
+        "keeper_job_max_rss_bytes",
+        "gauge",
+        "what was the largest resident set size of the last run of this job?",
+    );
+    e.define(
+        "keeper_job_block_input",
+        "gauge",
+        "how many block input operations did the last run of this job do?",
+    );
+    e.define(
+        "keeper_job_block_output",
+        "gauge",
+        "how many block output operations did the last run of this job do?",
+    );
+    e.define(
+        "keeper_job_voluntary_context_switches",
+        "gauge",
+        "how many times did the last run of this job yield the CPU?",
+    );
+    e.define(
+        "keeper_job_involuntary_context_switches",
+        "gauge",
+        "how many times was the last run of this job preempted?",
+    );
+    e.define(
+        "keeper_job_running",
+        "gauge",
+        "how many instances of this job are running on this host?",
+    );
+    e.define(
+        "keeper_job_running_seconds",
+        "gauge",
+        "for how long has the oldest running instance of this job run?",
+    );
+    e.define(
+        "keeper_job_overlapping",
+        "gauge",
+        "how many other instances of this job are running, on any host?",
+    );
+    e.define(
+        "keeper_job_overdue",
+        "gauge",
+        "has this job missed its expected schedule?",
+    );
+
     let summary = reports.summary(1).or_500()?;
+    for j in summary.iter() {
+        e.emit_i32("keeper_job_age_seconds", &j.host, &j.job, j.age_seconds);
+        e.emit_i32(
+            "keeper_job_duration_seconds",
+            &j.host,
+            &j.job,
+            j.duration_seconds,
+        );
+        e.emit_i32("keeper_job_ok", &j.host, &j.job, (j.status == 0) as i32);
+        e.emit_i32(
+            "keeper_job_output_truncated",
+            &j.host,
+            &j.job,
+            j.truncated as i32,
+        );
+        e.emit_i32(
+            "keeper_job_timed_out",
+            &j.host,
+            &j.job,
+            (j.outcome == ReportOutcome::TimedOut) as i32,
+        );
+        e.emit_i32("keeper_job_skipped", &j.host, &j.job, j.skipped as i32);
+        e.emit_i32(
+            "keeper_job_abandoned",
+            &j.host,
+            &j.job,
+            (j.outcome == ReportOutcome::Abandoned) as i32,
+        );
+
+        /*
+         * Older clients report only an exit status, from which we cannot tell
+         * a signal apart from a failure to wait for the process.
+         */
+        if let Some(t) = &j.termination {
+            let (signal, core, waitfail) = match t {
+                Termination::Exited { .. } => (0, false, false),
+                Termination::Signalled { signal } => (*signal, false, false),
+                Termination::CoreDumped { signal } => (*signal, true, false),
+                Termination::WaitError { .. } => (0, false, true),
+            };
+            e.emit_i32("keeper_job_signal", &j.host, &j.job, signal);
+            e.emit_i32("keeper_job_core_dumped", &j.host, &j.job, core as i32);
+            e.emit_i32(
+                "keeper_job_wait_failed",
+                &j.host,
+                &j.job,
+                waitfail as i32,
+            );
+        }
+
+        /*
+         * Resource usage is only available for jobs reported by newer
+         * clients.
+         */
+        if let Some(ru) = &j.rusage {
+            let i = |v: u64| i64::try_from(v).unwrap_or(i64::MAX);
+            e.emit_f64(
+                "keeper_job_cpu_user_seconds",
+                &j.host,
+                &j.job,
+                ru.user_cpu_millis as f64 / 1000.0,
+            );
+            e.emit_f64(
+                "keeper_job_cpu_system_seconds",
+                &j.host,
+                &j.job,
+                ru.system_cpu_millis as f64 / 1000.0,
+            );
+            e.emit_i64(
+                "keeper_job_max_rss_bytes",
+                &j.host,
+                &j.job,
+                i(ru.max_rss_kib.saturating_mul(1024)),
+            );
+            e.emit_i64(
+                "keeper_job_block_input",
+                &j.host,
+                &j.job,
+                i(ru.block_input),
+            );
+            e.emit_i64(
+                "keeper_job_block_output",
+                &j.host,
+                &j.job,
+                i(ru.block_output),
+            );
+            e.emit_i64(
+                "keeper_job_voluntary_context_switches",
+                &j.host,
+                &j.job,
+                i(ru.voluntary_context_switches),
+            );
+            e.emit_i64(
+                "keeper_job_involuntary_context_switches",
+                &j.host,
+                &j.job,
+                i(ru.involuntary_context_switches),
+            );
+        }
+    }
+
+    /*
+     * Report the running state of every job we know about, so that the
+     * gauges drop back to zero when nothing is running.
+     */
+    let mut running: HashMap<(String, String), (i32, i32, u32)> = summary
+        .into_iter()
+        .map(|j| ((j.host, j.job), (0, 0, 0)))
+        .collect();
+    for r in reports.running().or_500()?.into_iter() {
+        let e = running.entry((r.host, r.job)).or_default();
+        e.0 += 1;
+        e.1 = e.1.max(r.running_seconds);
+        e.2 = e.2.max(r.overlapping);
+    }
+    for ((host, job), (count, secs, overlapping)) in running.iter() {
+        e.emit_i32("keeper_job_running", host, job, *count);
+        if *count > 0 {
+            e.emit_i32("keeper_job_running_seconds", host, job, *secs);
+        }
+        e.emit_i32(
+            "keeper_job_overlapping",
+            host,
+            job,
+            i32::try_from(*overlapping).unwrap_or(i32::MAX),
+        );
+    }
+
+    for s in reports.schedule_status().or_500()?.iter() {
+        e.emit_i32("keeper_job_overdue", &s.host, &s.job, s.overdue as i32);
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "text/plain")
+        .body(Body::from(e.out().to_string()))?)
+}
+
+#[derive(Serialize, JsonSchema)]
+struct EnrolmentsResult {
+    enrolments: Vec<Enrolment>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/admin/enrolments",
+}]
+async fn admin_enrolments(
+    arc: RequestContext<App>,
+) -> SResult<HttpResponseOk<EnrolmentsResult>, HttpError> {
+    let app = arc.context();
+
+    app.require_admin(&arc.request).await?;
+
+    let keys = app.keys.read().await;
+    let enrolments = keys.list_enrolments().or_500()?;
+
+    Ok(HttpResponseOk(EnrolmentsResult { enrolments }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct HostPath {
+    host: String,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/admin/enrolments/{host}/approve",
+}]
+async fn admin_enrolment_approve(
+    arc: RequestContext<App>,
+    path: Path<HostPath>,
+) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    let app = arc.context();
+    let path = path.into_inner();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid name format".into(),
+        ));
+    }
+
+    let keys = app.keys.write().await;
+    match keys.approve_enrolment(&path.host) {
+        Ok(true) => {
+            info!(
+                arc.log,
+                "enrolment for {} approved by {}", path.host, auth.host
+            );
+            Ok(HttpResponseUpdatedNoContent())
+        }
+        Ok(false) => Err(HttpError::for_not_found(
+            None,
+            "no pending enrolment for this host".into(),
+        )),
+        Err(e) => {
+            if let Some(ae) = e.downcast_ref::<ApproveError>() {
+                Err(HttpError::for_client_error(
+                    None,
+                    StatusCode::CONFLICT,
+                    ae.to_string(),
+                ))
+            } else {
+                Err(e).or_500()
+            }
+        }
+    }
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/admin/enrolments/{host}",
+}]
+async fn admin_enrolment_reject(
+    arc: RequestContext<App>,
+    path: Path<HostPath>,
+) -> SResult<HttpResponseDeleted, HttpError> {
+    let app = arc.context();
+    let path = path.into_inner();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid name format".into(),
+        ));
+    }
+
+    let keys = app.keys.write().await;
+    if keys.reject_enrolment(&path.host).or_500()? {
+        info!(
+            arc.log,
+            "enrolment for {} rejected by {}", path.host, auth.host
+        );
+        Ok(HttpResponseDeleted())
+    } else {
+        Err(HttpError::for_not_found(
+            None,
+            "no pending enrolment for this host".into(),
+        ))
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+struct HostsResult {
+    hosts: Vec<HostInfo>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/admin/hosts",
+}]
+async fn admin_hosts(
+    arc: RequestContext<App>,
+) -> SResult<HttpResponseOk<HostsResult>, HttpError> {
+    let app = arc.context();
+
+    app.require_admin(&arc.request).await?;
+
+    let keys = app.keys.read().await;
+    let hosts = keys.list_hosts().or_500()?;
+
+    Ok(HttpResponseOk(HostsResult { hosts }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct HostUpdateBody {
+    global_view: Option<bool>,
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/admin/hosts/{host}",
+}]
+async fn admin_host_update(
+    arc: RequestContext<App>,
+    path: Path<HostPath>,
+    body: TypedBody<HostUpdateBody>,
+) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    let app = arc.context();
+    let path = path.into_inner();
+    let body = body.into_inner();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid name format".into(),
+        ));
+    }
+
+    let keys = app.keys.write().await;
+    if let Some(global_view) = body.global_view {
+        if !keys.set_global_view(&path.host, global_view).or_500()? {
+            return Err(HttpError::for_not_found(
+                None,
+                "no confirmed key for this host".into(),
+            ));
+        }
+        info!(
+            arc.log,
+            "global view for {} set to {} by {}",
+            path.host,
+            global_view,
+            auth.host
+        );
+    }
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/admin/hosts/{host}",
+}]
+async fn admin_host_revoke(
+    arc: RequestContext<App>,
+    path: Path<HostPath>,
+) -> SResult<HttpResponseDeleted, HttpError> {
+    let app = arc.context();
+    let path = path.into_inner();
 
-    Ok(HttpResponseCreated(GlobalJobsResult { summary }))
+    let auth = app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid name format".into(),
+        ));
+    }
+
+    let keys = app.keys.write().await;
+    if keys.revoke_host(&path.host).or_500()? {
+        info!(arc.log, "key for {} revoked by {}", path.host, auth.host);
+        Ok(HttpResponseDeleted())
+    } else {
+        Err(HttpError::for_not_found(
+            None,
+            "no confirmed key for this host".into(),
+        ))
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+struct KeysResult {
+    keys: Vec<KeyInfo>,
 }
 
 #[endpoint {
     method = GET,
-    path = "/global/metrics",
-    unpublished = true,
+    path = "/admin/hosts/{host}/keys",
 }]
-async fn global_metrics(
+async fn admin_host_keys(
     arc: RequestContext<App>,
-) -> SResult<Response<Body>, HttpError> {
+    path: Path<HostPath>,
+) -> SResult<HttpResponseOk<KeysResult>, HttpError> {
     let app = arc.context();
+    let path = path.into_inner();
 
-    let auth = app.require_auth(&arc.request).await?;
-    if !auth.global_view {
+    app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) {
         return Err(HttpError::for_client_error(
             None,
-            StatusCode::UNAUTHORIZED,
-            "uh uh uh".into(),
+            StatusCode::BAD_REQUEST,
+            "invalid name format".into(),
         ));
     }
 
-    let reports = app.reports.read().await;
+    let keys = app.keys.read().await;
+    if let Some(keys) = keys.list_keys(&path.host).or_500()? {
+        Ok(HttpResponseOk(KeysResult { keys }))
+    } else {
+        Err(HttpError::for_not_found(
+            None,
+            "no confirmed key for this host".into(),
+        ))
+    }
+}
 
-    let mut e = Emitter::new();
-    e.define("keeper_job_age_seconds", "gauge", "age of this job report");
-    e.define(
-        "keeper_job_ok",
-        "gauge",
-        "did the last run of this job exit 0?",
+#[derive(Deserialize, JsonSchema)]
+struct HostKeyPath {
+    host: String,
+    id: String,
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/admin/hosts/{host}/keys/{id}",
+}]
+async fn admin_host_key_revoke(
+    arc: RequestContext<App>,
+    path: Path<HostKeyPath>,
+) -> SResult<HttpResponseDeleted, HttpError> {
+    let app = arc.context();
+    let path = path.into_inner();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid name format".into(),
+        ));
+    }
+
+    let keys = app.keys.write().await;
+    if keys.revoke_key(&path.host, &path.id).or_500()? {
+        info!(
+            arc.log,
+            "key {} for {} revoked by {}", path.id, path.host, auth.host
+        );
+        Ok(HttpResponseDeleted())
+    } else {
+        Err(HttpError::for_not_found(
+            None,
+            "no such key for this host".into(),
+        ))
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+struct KeysReloadResult {
+    keys: u64,
+}
+
+/*
+ * Key files are indexed in memory, and changes to the "keys" directory are
+ * noticed automatically.  If a key file has been edited in place, an
+ * administrator may request that the index be rebuilt, either here or by
+ * sending SIGHUP to the server.
+ */
+#[endpoint {
+    method = POST,
+    path = "/admin/keys/reload",
+}]
+async fn admin_keys_reload(
+    arc: RequestContext<App>,
+) -> SResult<HttpResponseOk<KeysReloadResult>, HttpError> {
+    let app = arc.context();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    let keys = app.keys.write().await;
+    let count = keys.reload().or_500()?;
+    info!(arc.log, "key index reloaded by {}", auth.host);
+
+    Ok(HttpResponseOk(KeysReloadResult { keys: count as u64 }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct PruneBody {
+    /**
+     * Remove reports from before this time.
+     */
+    before: DateTime<Utc>,
+    host: Option<String>,
+    job: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct PruneResult {
+    removed: u64,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/admin/prune",
+}]
+async fn admin_prune(
+    arc: RequestContext<App>,
+    body: TypedBody<PruneBody>,
+) -> SResult<HttpResponseOk<PruneResult>, HttpError> {
+    let app = arc.context();
+    let body = body.into_inner();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    if body.host.iter().chain(body.job.iter()).any(|n| !name_ok(n)) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid host or job name".into(),
+        ));
+    }
+
+    let reports = app.reports.write().await;
+    let removed = reports
+        .prune(&body.before, body.host.as_deref(), body.job.as_deref())
+        .or_500()?;
+    info!(
+        arc.log,
+        "{} pruned {} reports from before {}", auth.host, removed, body.before
     );
-    e.define(
-        "keeper_job_duration_seconds",
-        "gauge",
-        "for how long did the last job run?",
+
+    Ok(HttpResponseOk(PruneResult {
+        removed: removed as u64,
+    }))
+}
+
+#[derive(Serialize, JsonSchema)]
+struct SchedulesResult {
+    schedules: Vec<ScheduleStatus>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/admin/schedules",
+}]
+async fn admin_schedules(
+    arc: RequestContext<App>,
+) -> SResult<HttpResponseOk<SchedulesResult>, HttpError> {
+    let app = arc.context();
+
+    app.require_admin(&arc.request).await?;
+
+    let reports = app.reports.read().await;
+    let schedules = reports.schedule_status().or_500()?;
+
+    Ok(HttpResponseOk(SchedulesResult { schedules }))
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/admin/schedules/{host}/{job}",
+}]
+async fn admin_schedule_set(
+    arc: RequestContext<App>,
+    path: Path<JobPath>,
+    body: TypedBody<Schedule>,
+) -> SResult<HttpResponseUpdatedNoContent, HttpError> {
+    let app = arc.context();
+    let path = path.into_inner();
+    let body = body.into_inner();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) || !name_ok(&path.job) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid host or job name".into(),
+        ));
+    }
+
+    if let Err(e) = body.check() {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            e.to_string(),
+        ));
+    }
+
+    let reports = app.reports.write().await;
+    reports
+        .set_schedule(&path.host, &path.job, &body)
+        .or_500()?;
+    info!(
+        arc.log,
+        "schedule for {}/{} set by {}", path.host, path.job, auth.host
     );
 
-    for j in reports.summary(1).or_500()?.iter() {
-        e.emit_i32("keeper_job_age_seconds", &j.host, &j.job, j.age_seconds);
-        e.emit_i32(
-            "keeper_job_duration_seconds",
-            &j.host,
-            &j.job,
-            j.duration_seconds,
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/admin/schedules/{host}/{job}",
+}]
+async fn admin_schedule_remove(
+    arc: RequestContext<App>,
+    path: Path<JobPath>,
+) -> SResult<HttpResponseDeleted, HttpError> {
+    let app = arc.context();
+    let path = path.into_inner();
+
+    let auth = app.require_admin(&arc.request).await?;
+
+    if !name_ok(&path.host) || !name_ok(&path.job) {
+        return Err(HttpError::for_client_error(
+            None,
+            StatusCode::BAD_REQUEST,
+            "invalid host or job name".into(),
+        ));
+    }
+
+    let reports = app.reports.write().await;
+    if reports.remove_schedule(&path.host, &path.job).or_500()? {
+        info!(
+            arc.log,
+            "schedule for {}/{} removed by {}", path.host, path.job, auth.host
         );
-        e.emit_i32("keeper_job_ok", &j.host, &j.job, (j.status == 0) as i32);
+        Ok(HttpResponseDeleted())
+    } else {
+        Err(HttpError::for_not_found(
+            None,
+            "no schedule for this job".into(),
+        ))
     }
+}
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header("content-type", "text/plain")
-        .body(Body::from(e.out().to_string()))?)
+/**
+ * Periodically check for jobs that have missed their expected schedule.
+ */
+async fn overdue_task(
+    log: Logger,
+    reports: Arc<RwLock<ReportStore>>,
+    notify: Arc<Notifier>,
+) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+
+        let res = reports.read().await.schedule_status();
+        match res {
+            Ok(schedules) => notify.schedules(&schedules),
+            Err(e) => error!(log, "checking schedules: {:?}", e),
+        }
+    }
+}
+
+/**
+ * Periodically seal any report from which we have not heard for too long, as
+ * the client has presumably gone away.
+ */
+async fn reaper_task(
+    log: Logger,
+    reports: Arc<RwLock<ReportStore>>,
+    notify: Arc<Notifier>,
+    seconds: u64,
+    limits: OutputLimits,
+) {
+    let window = chrono::Duration::seconds(seconds.min(u32::MAX.into()) as i64);
+
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+
+        let reports = reports.write().await;
+        let abandoned = match reports.abandon(&(Utc::now() - window), &limits) {
+            Ok(abandoned) => abandoned,
+            Err(e) => {
+                error!(log, "abandoning reports: {:?}", e);
+                continue;
+            }
+        };
+
+        for (host, job, time) in abandoned.iter() {
+            /*
+             * If the job has run again since, this report no longer tells us
+             * anything about whether the job is working.
+             */
+            match reports.history(host, job, None, Some(*time), 1) {
+                Ok(runs) if runs.iter().any(|r| r.time > *time) => continue,
+                Ok(_) => (),
+                Err(e) => {
+                    error!(log, "history for notify: {:?}", e);
+                    continue;
+                }
+            }
+
+            match reports.load(host, job, time) {
+                Ok(Some(f)) => notify.report(host, job, time, &f),
+                Ok(None) => (),
+                Err(e) => error!(log, "load for notify: {:?}", e),
+            }
+        }
+    }
+}
+
+/**
+ * Periodically remove reports that are older than the configured retention
+ * period.
+ */
+async fn retention_task(
+    log: Logger,
+    reports: Arc<RwLock<ReportStore>>,
+    days: u32,
+) {
+    loop {
+        let before = Utc::now() - chrono::Duration::days(days.into());
+
+        let res = reports.write().await.prune(&before, None, None);
+        if let Err(e) = res {
+            error!(log, "pruning reports: {:?}", e);
+        }
+
+        tokio::time::sleep(std::time::Duration::from_secs(86400)).await;
+    }
 }
 
 #[tokio::main]
@@ -568,8 +1998,10 @@ async fn main() -> Result<()> {
     let mut opts = Options::new();
 
     opts.optopt("b", "", "bind address:port", "BIND_ADDRESS");
+    opts.optopt("c", "", "configuration file", "FILE");
     opts.optopt("d", "", "data directory", "DIRECTORY");
     opts.optopt("S", "", "dump OpenAPI schema", "FILE");
+    opts.optflag("", "check-config", "check configuration and exit");
 
     let p = match opts.parse(std::env::args().skip(1)) {
         Ok(p) => p,
@@ -584,10 +2016,29 @@ async fn main() -> Result<()> {
     api.register(enrol).unwrap();
     api.register(report_start).unwrap();
     api.register(report_output).unwrap();
+    api.register(report_output_batch).unwrap();
+    api.register(report_heartbeat).unwrap();
     api.register(report_finish).unwrap();
+    api.register(report_get).unwrap();
+    api.register(report_history).unwrap();
     api.register(global_jobs).unwrap();
+    api.register(global_running).unwrap();
     api.register(global_metrics).unwrap();
     api.register(ping).unwrap();
+    api.register(key_rotate).unwrap();
+    api.register(admin_enrolments).unwrap();
+    api.register(admin_enrolment_approve).unwrap();
+    api.register(admin_enrolment_reject).unwrap();
+    api.register(admin_hosts).unwrap();
+    api.register(admin_host_update).unwrap();
+    api.register(admin_host_revoke).unwrap();
+    api.register(admin_host_keys).unwrap();
+    api.register(admin_host_key_revoke).unwrap();
+    api.register(admin_keys_reload).unwrap();
+    api.register(admin_prune).unwrap();
+    api.register(admin_schedules).unwrap();
+    api.register(admin_schedule_set).unwrap();
+    api.register(admin_schedule_remove).unwrap();
 
     if let Some(s) = p.opt_str("S") {
         let mut f = std::fs::OpenOptions::new()
@@ -605,46 +2056,151 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let bind = p
-        .opt_str("b")
-        .unwrap_or_else(|| String::from("0.0.0.0:9978"));
-    let dir = if let Some(d) = p.opt_str("d") {
-        PathBuf::from(d)
+    /*
+     * Options on the command line override those in the configuration file.
+     */
+    let mut config = if let Some(c) = p.opt_str("c") {
+        Config::load(&PathBuf::from(c))?
     } else {
-        bail!("ERROR: must specify data directory (-d)");
+        Config::default()
     };
-    if !dir.is_dir() {
-        bail!("ERROR: {} should be a directory", dir.display());
+    if let Some(b) = p.opt_str("b") {
+        config.bind_address = b.parse()?;
+    }
+    if let Some(d) = p.opt_str("d") {
+        config.data_dir = Some(PathBuf::from(d));
     }
 
-    let cfglog = ConfigLogging::StderrTerminal {
-        level: ConfigLoggingLevel::Info,
-    };
-    let log = cfglog.to_logger("keeper")?;
+    if let Err(e) = config.check() {
+        bail!("ERROR: invalid configuration: {:?}", e);
+    }
+    if p.opt_present("check-config") {
+        println!("configuration ok");
+        return Ok(());
+    }
+    let dir = config.data_dir.clone().unwrap();
+
+    let log = config.log.to_logger("keeper")?;
 
     let keylog = log.new(o!("component" => "keystore"));
     let keys = RwLock::new(KeyStore::new(keylog, dir.clone())?);
 
     let reportlog = log.new(o!("component" => "reportstore"));
-    let reports = RwLock::new(ReportStore::new(reportlog, dir.clone())?);
+    let reports =
+        Arc::new(RwLock::new(ReportStore::new(reportlog, dir.clone())?));
+
+    let notifylog = log.new(o!("component" => "notify"));
+    let notify = Notifier::new(notifylog, dir.clone(), config.notify.clone())?;
+
+    tokio::spawn(overdue_task(
+        log.clone(),
+        Arc::clone(&reports),
+        Arc::clone(&notify),
+    ));
+
+    if let Some(days) = config.retention.days {
+        tokio::spawn(retention_task(log.clone(), Arc::clone(&reports), days));
+    }
+
+    if config.heartbeat.abandon_seconds > 0 {
+        tokio::spawn(reaper_task(
+            log.clone(),
+            Arc::clone(&reports),
+            Arc::clone(&notify),
+            config.heartbeat.abandon_seconds,
+            config.limits.output(),
+        ));
+    }
+
+    /*
+     * If hosts may authenticate with client certificates, we must terminate
+     * TLS ourselves.  The dropshot server then listens for plain HTTP from
+     * us, on the loopback interface only.
+     */
+    let client_certs = if let Some(cc) = &config.client_certs {
+        let ccl = log.new(o!("component" => "clientcert"));
+        let tls = config.tls.clone().unwrap();
+        let listener =
+            tokio::net::TcpListener::bind(config.bind_address).await?;
+        Some((ClientCerts::new(ccl, tls, cc.clone())?, listener))
+    } else {
+        None
+    };
 
     let app = App {
         log: log.clone(),
         keys,
         reports,
+        notify,
+        client_certs: client_certs.as_ref().map(|(cc, _)| Arc::clone(cc)),
+        output_limits: config.limits.output(),
     };
 
     let cfgds = ConfigDropshot {
-        bind_address: bind.parse()?,
-        request_body_max_bytes: 1024 * 1024,
+        bind_address: if client_certs.is_some() {
+            "127.0.0.1:0".parse().unwrap()
+        } else {
+            config.bind_address
+        },
+        request_body_max_bytes: config.limits.request_body_max_bytes,
         ..Default::default()
     };
 
-    let server = HttpServerStarter::new(&cfgds, api, app, &log)
-        .map_err(|e| anyhow!("server starter failure: {:?}", e))?;
-    server
-        .start()
-        .await
-        .map_err(|e| anyhow!("server task failure: {:?}", e))?;
-    bail!("early exit is unexpected");
+    let tls = if client_certs.is_some() {
+        None
+    } else {
+        config.tls.as_ref().map(|tls| ConfigTls::AsFile {
+            cert_file: tls.cert_file.clone(),
+            key_file: tls.key_file.clone(),
+        })
+    };
+
+    let server =
+        HttpServerStarter::new_with_tls(&cfgds, api, app, &log, tls.clone())
+            .map_err(|e| anyhow!("server starter failure: {:?}", e))?
+            .start();
+    tokio::pin!(server);
+
+    let client_certs = if let Some((cc, listener)) = client_certs {
+        tokio::spawn(Arc::clone(&cc).serve(listener, server.local_addr()));
+        Some(cc)
+    } else {
+        None
+    };
+
+    /*
+     * Reload the key index and the TLS certificate and key from disk when we
+     * receive SIGHUP, so that key files edited in place are noticed and
+     * certificates can be renewed without restarting the server.
+     */
+    let mut hup = signal(SignalKind::hangup())?;
+
+    loop {
+        tokio::select! {
+            res = &mut server => {
+                res.map_err(|e| anyhow!("server task failure: {:?}", e))?;
+                bail!("early exit is unexpected");
+            }
+            _ = hup.recv() => {
+                info!(log, "reloading keys");
+                let keys = server.app_private().keys.write().await;
+                if let Err(e) = keys.reload() {
+                    error!(log, "key reload failed: {:?}", e);
+                }
+                drop(keys);
+
+                if let Some(cc) = &client_certs {
+                    info!(log, "reloading TLS certificates");
+                    if let Err(e) = cc.reload() {
+                        error!(log, "TLS certificate reload failed: {:?}", e);
+                    }
+                } else if let Some(tls) = &tls {
+                    info!(log, "reloading TLS certificate");
+                    if let Err(e) = server.refresh_tls(tls).await {
+                        error!(log, "TLS certificate reload failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,511 @@
+/*
+ * Notifications about the state of jobs, delivered to the webhooks and mail
+ * recipients listed in the notification configuration file.
+ */
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::prelude::*;
+use keeper_common::*;
+use keeper_store::{PostFile, ReportOutcome, ScheduleStatus};
+use serde::{Deserialize, Serialize};
+#[allow(unused_imports)]
+use slog::{debug, error, info, o, warn, Logger};
+
+use crate::smtp::{MailRule, SmtpConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /**
+     * A job finished with a non-zero exit status.
+     */
+    Failure,
+    /**
+     * A job that had previously failed, was abandoned, or was overdue,
+     * finished successfully.
+     */
+    Recovery,
+    /**
+     * A job did not start by the deadline implied by its expected schedule.
+     */
+    Overdue,
+    /**
+     * We stopped hearing from the client while a job was running.
+     */
+    Abandoned,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /**
+     * Which events to deliver to this webhook.  If not specified, all events
+     * are delivered.
+     */
+    #[serde(default)]
+    pub events: Option<Vec<Event>>,
+}
+
+fn default_quiet_seconds() -> u64 {
+    3600
+}
+
+fn default_tail_lines() -> usize {
+    20
+}
+
+fn default_attempts() -> u32 {
+    5
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub smtp: Option<SmtpConfig>,
+    /**
+     * A job that flaps between failure and success would otherwise generate
+     * a pair of notifications for every run.  We will not send the same
+     * event for the same job more than once within this period.
+     */
+    #[serde(default = "default_quiet_seconds")]
+    pub quiet_seconds: u64,
+    /**
+     * How many lines from the end of the job output to include.
+     */
+    #[serde(default = "default_tail_lines")]
+    pub tail_lines: usize,
+    /**
+     * How many times to attempt delivery to a webhook before giving up.
+     */
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+}
+
+impl NotifyConfig {
+    pub fn check(&self) -> Result<()> {
+        for wh in self.webhooks.iter() {
+            if let Err(e) = reqwest::Url::parse(&wh.url) {
+                bail!("invalid webhook URL {:?}: {}", wh.url, e);
+            }
+        }
+
+        if let Some(smtp) = &self.smtp {
+            smtp.check()?;
+        }
+
+        if self.attempts == 0 {
+            bail!("must attempt delivery at least once");
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            webhooks: Default::default(),
+            smtp: None,
+            quiet_seconds: default_quiet_seconds(),
+            tail_lines: default_tail_lines(),
+            attempts: default_attempts(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Notification {
+    pub event: Event,
+    pub host: String,
+    pub job: String,
+    /**
+     * The time of the report that triggered the notification, or for an
+     * overdue job, the deadline that was missed.
+     */
+    pub time: DateTime<Utc>,
+    pub status: Option<i32>,
+    pub duration_seconds: Option<i32>,
+    pub script: Option<String>,
+    pub output: Vec<String>,
+}
+
+/**
+ * Somewhere we can deliver a notification.
+ */
+#[derive(Clone)]
+enum Target {
+    Webhook(WebhookConfig),
+    Mail(MailRule),
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Webhook(wh) => write!(f, "webhook {}", wh.url),
+            Target::Mail(rule) => write!(f, "mail to {}", rule.to.join(", ")),
+        }
+    }
+}
+
+/**
+ * To avoid repeating ourselves, we track what we last said about each job.
+ * This is stored in the data directory so that a restart of the server does
+ * not cause a fresh round of notifications.
+ */
+#[derive(Default, Serialize, Deserialize)]
+struct JobState {
+    #[serde(default)]
+    failing: bool,
+    #[serde(default)]
+    overdue: bool,
+    #[serde(default)]
+    last_sent: HashMap<Event, DateTime<Utc>>,
+    /**
+     * The time of the newest report we have seen for the job.
+     */
+    #[serde(default)]
+    latest: Option<DateTime<Utc>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct NotifyState {
+    #[serde(default)]
+    jobs: HashMap<String, JobState>,
+}
+
+pub struct Notifier {
+    log: Logger,
+    config: NotifyConfig,
+    path: PathBuf,
+    state: Mutex<NotifyState>,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new<P: AsRef<Path>>(
+        log: Logger,
+        dir: P,
+        config: NotifyConfig,
+    ) -> Result<Arc<Notifier>> {
+        config.check()?;
+
+        let mut path = dir.as_ref().to_path_buf();
+        path.push("notify.json");
+
+        let state = load_file(&path)?.unwrap_or_default();
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Arc::new(Notifier {
+            log,
+            config,
+            path,
+            state: Mutex::new(state),
+            client,
+        }))
+    }
+
+    fn save(&self, state: &NotifyState) {
+        if let Err(e) = store_file(&self.path, state, false) {
+            error!(self.log, "could not store notification state: {:?}", e);
+        }
+    }
+
+    /**
+     * Record that an event has occurred for a job, returning true if we
+     * should tell anybody about it.
+     */
+    fn should_send(&self, js: &mut JobState, event: Event) -> bool {
+        let now = Utc::now();
+        let quiet = chrono::Duration::seconds(
+            i64::try_from(self.config.quiet_seconds).unwrap_or(i64::MAX),
+        );
+
+        if let Some(last) = js.last_sent.get(&event) {
+            if now.signed_duration_since(*last) < quiet {
+                return false;
+            }
+        }
+
+        js.last_sent.insert(event, now);
+        true
+    }
+
+    /**
+     * Called whenever a report is sealed, to determine if the job has failed
+     * or recovered.
+     */
+    pub fn report(
+        self: &Arc<Self>,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+        post: &PostFile,
+    ) {
+        /*
+         * A skipped run tells us nothing about whether the job is working.
+         * An abandoned run has no exit status, but is treated as a failure.
+         */
+        let (ok, bad) = match (post.outcome, post.status) {
+            (Some(ReportOutcome::Skipped), _) => return,
+            (Some(ReportOutcome::Abandoned), _) => (false, Event::Abandoned),
+            (_, Some(status)) => (status == 0, Event::Failure),
+            (_, None) => return,
+        };
+
+        let event = {
+            let mut state = self.state.lock().unwrap();
+            let js = state.jobs.entry(format!("{}/{}", host, job)).or_default();
+
+            /*
+             * Reports can be sealed well after newer runs of the same job;
+             * e.g., when replayed from a client spool, or when abandoned.
+             * These say nothing about the current state of the job.
+             */
+            if js.latest.map(|l| *time < l).unwrap_or(false) {
+                return;
+            }
+            js.latest = Some(*time);
+
+            let event = if !ok {
+                let was_failing = js.failing;
+                js.failing = true;
+                if was_failing {
+                    None
+                } else {
+                    Some(bad)
+                }
+            } else {
+                let was_bad = js.failing || js.overdue;
+                js.failing = false;
+                js.overdue = false;
+                if was_bad {
+                    Some(Event::Recovery)
+                } else {
+                    None
+                }
+            };
+
+            let event = event.filter(|e| self.should_send(js, *e));
+            self.save(&state);
+            event
+        };
+
+        if let Some(event) = event {
+            let count = post.output_records().count();
+            let skip = count.saturating_sub(self.config.tail_lines);
+
+            self.send(Notification {
+                event,
+                host: host.to_string(),
+                job: job.to_string(),
+                time: *time,
+                status: post.status,
+                duration_seconds: post.duration_seconds(),
+                script: Some(post.script.to_string()),
+                output: post
+                    .output_records()
+                    .skip(skip)
+                    .map(|o| o.msg.to_string())
+                    .collect(),
+            });
+        }
+    }
+
+    /**
+     * Called periodically with the current state of all scheduled jobs, to
+     * determine if any have become overdue.
+     */
+    pub fn schedules(self: &Arc<Self>, schedules: &[ScheduleStatus]) {
+        let mut out = Vec::new();
+
+        {
+            let mut state = self.state.lock().unwrap();
+            let mut dirty = false;
+
+            for s in schedules.iter() {
+                let key = format!("{}/{}", s.host, s.job);
+                let js = state.jobs.entry(key).or_default();
+
+                /*
+                 * The overdue flag is cleared only once the job next
+                 * completes successfully, so that we can report the
+                 * recovery.
+                 */
+                if !s.overdue || js.overdue {
+                    continue;
+                }
+                js.overdue = true;
+                dirty = true;
+
+                if self.should_send(js, Event::Overdue) {
+                    out.push(Notification {
+                        event: Event::Overdue,
+                        host: s.host.to_string(),
+                        job: s.job.to_string(),
+                        time: s.deadline,
+                        status: None,
+                        duration_seconds: None,
+                        script: None,
+                        output: Vec::new(),
+                    });
+                }
+            }
+
+            if dirty {
+                self.save(&state);
+            }
+        }
+
+        for n in out {
+            self.send(n);
+        }
+    }
+
+    fn send(self: &Arc<Self>, n: Notification) {
+        info!(self.log, "{:?} for {}/{}", n.event, n.host, n.job);
+
+        let mut targets = Vec::new();
+        for wh in self.config.webhooks.iter() {
+            if let Some(events) = &wh.events {
+                if !events.contains(&n.event) {
+                    continue;
+                }
+            }
+            targets.push(Target::Webhook(wh.clone()));
+        }
+        if let Some(smtp) = &self.config.smtp {
+            for rule in smtp.rules.iter() {
+                if rule.matches(&n) {
+                    targets.push(Target::Mail(rule.clone()));
+                }
+            }
+        }
+
+        for t in targets {
+            let notifier = Arc::clone(self);
+            let n = n.clone();
+            tokio::spawn(async move {
+                notifier.deliver(&t, &n).await;
+            });
+        }
+    }
+
+    async fn deliver(&self, t: &Target, n: &Notification) {
+        let mut delay = 1;
+
+        for attempt in 1..=self.config.attempts {
+            match self.post(t, n).await {
+                Ok(()) => {
+                    debug!(self.log, "delivered to {}", t);
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        self.log,
+                        "{} attempt {}/{} failed: {:?}",
+                        t,
+                        attempt,
+                        self.config.attempts,
+                        e
+                    );
+                }
+            }
+
+            if attempt < self.config.attempts {
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+                delay = (delay * 2).min(300);
+            }
+        }
+
+        error!(
+            self.log,
+            "giving up on {:?} for {}/{} to {}", n.event, n.host, n.job, t
+        );
+    }
+
+    async fn post(&self, t: &Target, n: &Notification) -> Result<()> {
+        match t {
+            Target::Webhook(wh) => {
+                let res = self.client.post(&wh.url).json(n).send().await?;
+
+                if !res.status().is_success() {
+                    bail!("status {}", res.status());
+                }
+            }
+            Target::Mail(rule) => {
+                if let Some(smtp) = &self.config.smtp {
+                    smtp.send(rule, n).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier(quiet_seconds: u64) -> Arc<Notifier> {
+        /*
+         * The state file is only read when the notifier is created, and is
+         * not written by should_send(), so the directory need not exist.
+         */
+        let dir = std::env::temp_dir()
+            .join(format!("keeper-notify-test-{}", std::process::id()));
+        let log = Logger::root(slog::Discard, o!());
+        let config = NotifyConfig {
+            quiet_seconds,
+            ..Default::default()
+        };
+        Notifier::new(log, dir, config).unwrap()
+    }
+
+    #[test]
+    fn quiet_period() {
+        let n = notifier(3600);
+        let mut js = JobState::default();
+
+        assert!(n.should_send(&mut js, Event::Failure));
+        assert!(!n.should_send(&mut js, Event::Failure));
+
+        /*
+         * The quiet period applies to each event separately.
+         */
+        assert!(n.should_send(&mut js, Event::Recovery));
+        assert!(!n.should_send(&mut js, Event::Recovery));
+        assert!(!n.should_send(&mut js, Event::Failure));
+
+        /*
+         * Once the period has elapsed, the event may be sent again.
+         */
+        js.last_sent.insert(
+            Event::Failure,
+            Utc::now() - chrono::Duration::seconds(3601),
+        );
+        assert!(n.should_send(&mut js, Event::Failure));
+        assert!(!n.should_send(&mut js, Event::Failure));
+    }
+
+    #[test]
+    fn no_quiet_period() {
+        let n = notifier(0);
+        let mut js = JobState::default();
+
+        assert!(n.should_send(&mut js, Event::Failure));
+        assert!(n.should_send(&mut js, Event::Failure));
+    }
+}
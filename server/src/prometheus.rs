@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 
 struct EmitterStat {
     name: String,
@@ -45,12 +46,12 @@ impl Emitter {
         self.printed.insert(stat_name.to_string());
     }
 
-    pub fn emit_i64(
+    fn emit<T: Display>(
         &mut self,
         stat_name: &str,
         host: &str,
         job: &str,
-        val: i64,
+        val: T,
     ) {
         self.emit_header(stat_name);
 
@@ -61,6 +62,26 @@ impl Emitter {
         );
     }
 
+    pub fn emit_f64(
+        &mut self,
+        stat_name: &str,
+        host: &str,
+        job: &str,
+        val: f64,
+    ) {
+        self.emit(stat_name, host, job, val);
+    }
+
+    pub fn emit_i64(
+        &mut self,
+        stat_name: &str,
+        host: &str,
+        job: &str,
+        val: i64,
+    ) {
+        self.emit(stat_name, host, job, val);
+    }
+
     pub fn emit_i32(
         &mut self,
         stat_name: &str,
@@ -0,0 +1,347 @@
+/*
+ * Delivery of notifications as mail, through an SMTP relay.
+ */
+
+use anyhow::{anyhow, bail, Result};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Deserialize;
+
+use crate::notify::{Event, Notification};
+
+fn default_port() -> u16 {
+    25
+}
+
+fn default_events() -> Vec<Event> {
+    vec![Event::Failure]
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub relay: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /**
+     * Use STARTTLS when talking to the relay.  This is off by default, so that
+     * a relay on the local system or a stand-in server used for testing can be
+     * used without certificates.
+     */
+    #[serde(default)]
+    pub starttls: bool,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    #[serde(default)]
+    pub rules: Vec<MailRule>,
+}
+
+/**
+ * Mail about jobs that match the host and job patterns is sent to the listed
+ * recipients.  Patterns may include shell-style wildcards, and if a pattern
+ * is not specified, it matches everything.
+ */
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailRule {
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub job: Option<String>,
+    pub to: Vec<String>,
+    /**
+     * By default, mail is only sent when a job fails.
+     */
+    #[serde(default = "default_events")]
+    pub events: Vec<Event>,
+}
+
+fn pattern_ok(pat: &Option<String>) -> Result<()> {
+    if let Some(pat) = pat {
+        glob::Pattern::new(pat)
+            .map_err(|e| anyhow!("invalid pattern {:?}: {}", pat, e))?;
+    }
+    Ok(())
+}
+
+fn pattern_matches(pat: &Option<String>, s: &str) -> bool {
+    if let Some(pat) = pat {
+        glob::Pattern::new(pat)
+            .map(|p| p.matches(s))
+            .unwrap_or(false)
+    } else {
+        true
+    }
+}
+
+impl MailRule {
+    pub fn matches(&self, n: &Notification) -> bool {
+        self.events.contains(&n.event)
+            && pattern_matches(&self.host, &n.host)
+            && pattern_matches(&self.job, &n.job)
+    }
+}
+
+impl SmtpConfig {
+    /**
+     * Check the configuration at startup, rather than discovering a problem
+     * only when a job fails.
+     */
+    pub fn check(&self) -> Result<()> {
+        if self.username.is_some() != self.password.is_some() {
+            bail!("SMTP username and password must be specified together");
+        }
+
+        self.from.parse::<Mailbox>().map_err(|e| {
+            anyhow!("invalid from address {:?}: {}", self.from, e)
+        })?;
+
+        for r in self.rules.iter() {
+            pattern_ok(&r.host)?;
+            pattern_ok(&r.job)?;
+
+            if r.to.is_empty() {
+                bail!("mail rule must have at least one recipient");
+            }
+            for to in r.to.iter() {
+                to.parse::<Mailbox>()
+                    .map_err(|e| anyhow!("invalid address {:?}: {}", to, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compose(&self, rule: &MailRule, n: &Notification) -> Result<Message> {
+        let what = match n.event {
+            Event::Failure => "failed",
+            Event::Recovery => "recovered",
+            Event::Overdue => "is overdue",
+            Event::Abandoned => "was abandoned",
+        };
+        let subject = format!("keeper: {}/{} {}", n.host, n.job, what);
+
+        let mut body = match n.event {
+            Event::Overdue => format!(
+                "Job \"{}\" on host \"{}\" did not start by {}.\n",
+                n.job, n.host, n.time,
+            ),
+            _ => format!(
+                "Job \"{}\" on host \"{}\" {}.\n\n\
+                Started: {}\n\
+                Exit status: {}\n\
+                Duration: {}\n",
+                n.job,
+                n.host,
+                what,
+                n.time,
+                n.status
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "-".into()),
+                n.duration_seconds
+                    .map(|d| format!("{}s", d))
+                    .unwrap_or_else(|| "-".into()),
+            ),
+        };
+
+        if let Some(script) = &n.script {
+            body += "\nScript:\n";
+            for l in script.lines() {
+                body += &format!("    {}\n", l);
+            }
+        }
+
+        if !n.output.is_empty() {
+            body += &format!("\nLast {} lines of output:\n", n.output.len());
+            for l in n.output.iter() {
+                body += &format!("    {}\n", l);
+            }
+        }
+
+        let mut m = Message::builder()
+            .from(self.from.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in rule.to.iter() {
+            m = m.to(to.parse()?);
+        }
+
+        Ok(m.body(body)?)
+    }
+
+    pub async fn send(&self, rule: &MailRule, n: &Notification) -> Result<()> {
+        let m = self.compose(rule, n)?;
+
+        let mut t = if self.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.relay)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.relay)
+        }
+        .port(self.port);
+
+        if let (Some(u), Some(p)) = (&self.username, &self.password) {
+            t = t.credentials(Credentials::new(u.to_string(), p.to_string()));
+        }
+
+        t.build().send(m).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    fn notification(event: Event, host: &str, job: &str) -> Notification {
+        Notification {
+            event,
+            host: host.to_string(),
+            job: job.to_string(),
+            time: Utc.with_ymd_and_hms(2024, 5, 1, 3, 0, 0).unwrap(),
+            status: Some(1),
+            duration_seconds: Some(42),
+            script: Some("backup --all".to_string()),
+            output: vec!["disk full".to_string()],
+        }
+    }
+
+    fn rule(host: Option<&str>, job: Option<&str>) -> MailRule {
+        MailRule {
+            host: host.map(str::to_string),
+            job: job.map(str::to_string),
+            to: vec!["ops@example.com".to_string()],
+            events: default_events(),
+        }
+    }
+
+    #[test]
+    fn rule_patterns() {
+        let n = notification(Event::Failure, "web01", "backup");
+
+        assert!(rule(None, None).matches(&n));
+        assert!(rule(Some("web*"), None).matches(&n));
+        assert!(rule(Some("web0[0-9]"), Some("back?p")).matches(&n));
+        assert!(!rule(Some("db*"), None).matches(&n));
+        assert!(!rule(None, Some("backup-*")).matches(&n));
+
+        /*
+         * An invalid pattern is rejected by the configuration check, but
+         * should never match anything if it gets this far.
+         */
+        assert!(!rule(Some("web[01"), None).matches(&n));
+    }
+
+    #[test]
+    fn rule_events() {
+        let r = rule(None, None);
+        assert!(r.matches(&notification(Event::Failure, "web01", "backup")));
+        assert!(!r.matches(&notification(Event::Recovery, "web01", "backup")));
+
+        let r = MailRule {
+            events: vec![Event::Overdue, Event::Abandoned],
+            ..rule(None, None)
+        };
+        assert!(!r.matches(&notification(Event::Failure, "web01", "backup")));
+        assert!(r.matches(&notification(Event::Overdue, "web01", "backup")));
+        assert!(r.matches(&notification(Event::Abandoned, "web01", "backup")));
+    }
+
+    /**
+     * What a stand-in relay heard from the client.
+     */
+    #[derive(Default)]
+    struct Received {
+        from: Vec<String>,
+        to: Vec<String>,
+        data: String,
+    }
+
+    /**
+     * Accept a single connection and speak just enough SMTP to receive one
+     * message.
+     */
+    fn relay(l: TcpListener) -> Received {
+        let (s, _) = l.accept().unwrap();
+        let mut w = s.try_clone().unwrap();
+        let mut r = BufReader::new(s);
+        let mut rx = Received::default();
+
+        w.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+        loop {
+            let mut line = String::new();
+            if r.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end();
+            let cmd = line.to_ascii_uppercase();
+
+            if cmd.starts_with("MAIL FROM:") {
+                rx.from.push(line[10..].to_string());
+            } else if cmd.starts_with("RCPT TO:") {
+                rx.to.push(line[8..].to_string());
+            } else if cmd == "DATA" {
+                w.write_all(b"354 go ahead\r\n").unwrap();
+                loop {
+                    let mut line = String::new();
+                    r.read_line(&mut line).unwrap();
+                    if line == ".\r\n" {
+                        break;
+                    }
+                    rx.data += &line;
+                }
+            } else if cmd == "QUIT" {
+                w.write_all(b"221 bye\r\n").unwrap();
+                break;
+            }
+
+            w.write_all(b"250 ok\r\n").unwrap();
+        }
+
+        rx
+    }
+
+    #[tokio::test]
+    async fn send_mail() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = l.local_addr().unwrap().port();
+        let t = std::thread::spawn(move || relay(l));
+
+        let r = MailRule {
+            to: vec![
+                "ops@example.com".to_string(),
+                "Backup Team <backups@example.com>".to_string(),
+            ],
+            ..rule(None, None)
+        };
+        let c = SmtpConfig {
+            relay: "127.0.0.1".to_string(),
+            port,
+            starttls: false,
+            username: None,
+            password: None,
+            from: "keeper@example.com".to_string(),
+            rules: vec![r.clone()],
+        };
+        c.check().unwrap();
+
+        let n = notification(Event::Failure, "web01", "backup");
+        c.send(&r, &n).await.unwrap();
+
+        let rx = t.join().unwrap();
+        assert_eq!(rx.from, vec!["<keeper@example.com>"]);
+        assert_eq!(rx.to, vec!["<ops@example.com>", "<backups@example.com>"]);
+        assert!(rx.data.contains("Subject: keeper: web01/backup failed\r\n"));
+        assert!(rx.data.contains("Exit status: 1\r\n"));
+        assert!(rx.data.contains("Duration: 42s\r\n"));
+        assert!(rx.data.contains("    backup --all\r\n"));
+        assert!(rx.data.contains("    disk full\r\n"));
+    }
+}
@@ -0,0 +1,3271 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::prelude::*;
+use keeper_common::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+#[allow(unused_imports)]
+use slog::{debug, error, info, warn, Logger};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+
+#[derive(Serialize, Deserialize)]
+pub struct KeyFile {
+    pub host: String,
+    /**
+     * Key files written before hosts could hold more than one key have just
+     * this single key, which is folded into "keys" when the file is loaded.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub keys: Vec<HostKey>,
+    pub time_create: DateTime<Utc>,
+    #[serde(default)]
+    pub global_view: bool,
+    /**
+     * Administrative keys may approve or reject enrolments, and otherwise
+     * manage the server through the API.  The first such key must be
+     * marked by hand in "keys/<hostname>.json", after which the server must
+     * be sent SIGHUP to notice the change.
+     */
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl KeyFile {
+    fn load(p: &Path) -> Result<Option<KeyFile>> {
+        let mut f: KeyFile = if let Some(f) = load_file(p)? {
+            f
+        } else {
+            return Ok(None);
+        };
+
+        if let Some(key) = f.key.take() {
+            f.keys.insert(
+                0,
+                HostKey {
+                    id: "initial".to_string(),
+                    key: Some(key),
+                    hash: None,
+                    time_create: f.time_create,
+                    time_expire: None,
+                    time_revoke: None,
+                },
+            );
+        }
+
+        Ok(Some(f))
+    }
+}
+
+/**
+ * Keys are stored as a salted SHA-256 hash.  Keys are long and generated at
+ * random by the client, so a slow password hash is not required to resist
+ * guessing.
+ *
+ * So that we can find a key without checking every salted hash in turn, we
+ * also store the unsalted SHA-256 hash of the key as an index.  As the keys
+ * are random, this gives nothing away beyond whether two hosts share a key,
+ * which is a condition we must be able to detect anyway.
+ */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyHash {
+    pub salt: String,
+    pub sha256: String,
+    /**
+     * Hashes stored before the index was added do not have one; it is filled
+     * in the first time the key is used.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+
+    let mut out = [0u8; 32];
+    for (i, o) in out.iter_mut().enumerate() {
+        *o = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+fn salted_hash(salt: &str, key: &str) -> String {
+    let mut h = Sha256::new();
+    h.update(salt.as_bytes());
+    h.update(key.as_bytes());
+    hex(&h.finalize())
+}
+
+impl KeyHash {
+    fn new(key: &str) -> KeyHash {
+        let salt = genkey(16);
+        KeyHash {
+            sha256: salted_hash(&salt, key),
+            salt,
+            index: Some(hex(&key_hash(key))),
+        }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        let sha256 = salted_hash(&self.salt, key);
+        sha256.as_bytes().ct_eq(self.sha256.as_bytes()).into()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HostKey {
+    pub id: String,
+    /**
+     * Keys stored before we began to hash them are stored in plain text.
+     * These are replaced with a hash the first time they are used.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<KeyHash>,
+    pub time_create: DateTime<Utc>,
+    #[serde(default)]
+    pub time_expire: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub time_revoke: Option<DateTime<Utc>>,
+}
+
+impl HostKey {
+    fn new(key: &str) -> HostKey {
+        HostKey {
+            id: genkey(8),
+            key: None,
+            hash: Some(KeyHash::new(key)),
+            time_create: Utc::now(),
+            time_expire: None,
+            time_revoke: None,
+        }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        if let Some(hash) = &self.hash {
+            hash.matches(key)
+        } else if let Some(k) = &self.key {
+            k.as_bytes().ct_eq(key.as_bytes()).into()
+        } else {
+            false
+        }
+    }
+
+    fn valid(&self, now: &DateTime<Utc>) -> bool {
+        self.time_revoke.is_none()
+            && self.time_expire.map(|t| *now < t).unwrap_or(true)
+    }
+
+    /**
+     * The hash by which this key is found in the key index, if we have it.
+     */
+    fn index(&self) -> Option<[u8; 32]> {
+        if let Some(hash) = &self.hash {
+            hash.index.as_deref().and_then(unhex)
+        } else {
+            self.key.as_deref().map(key_hash)
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct KeyInfo {
+    pub id: String,
+    pub time_create: DateTime<Utc>,
+    pub time_expire: Option<DateTime<Utc>>,
+    pub time_revoke: Option<DateTime<Utc>>,
+    pub valid: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct HostInfo {
+    pub host: String,
+    pub time_create: DateTime<Utc>,
+    pub global_view: bool,
+    pub admin: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Enrolment {
+    pub host: String,
+    pub time_create: DateTime<Utc>,
+}
+
+/**
+ * Reasons an enrolment cannot be approved that are not a failure of the
+ * store itself.
+ */
+#[derive(Debug)]
+pub enum ApproveError {
+    /**
+     * The host already has a confirmed key.
+     */
+    AlreadyConfirmed { host: String },
+    /**
+     * The key in the enrolment is already in use by another host.
+     */
+    KeyInUse { host: String, other: String },
+}
+
+impl std::fmt::Display for ApproveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApproveError::AlreadyConfirmed { host } => {
+                write!(f, "host {} already has a confirmed key", host)
+            }
+            ApproveError::KeyInUse { host, other } => {
+                write!(
+                    f,
+                    "enrolment key for {} already in use by {}",
+                    host, other
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApproveError {}
+
+/**
+ * Host and job names must be safe as a filename, as we will store the
+ * associated user account in "keys/<hostname>.json" and jobs are stored as
+ * "reports/<hostname>/<jobname>/...".
+ */
+pub fn name_ok(n: &str) -> bool {
+    n.chars().all(|c| {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-'
+    }) && n.len() >= 2
+}
+
+/**
+ * Clients submit their own key during enrolment, and it will be included in
+ * "Authorization" headers later.  Make sure the format is at least plausible.
+ */
+pub fn key_ok(k: &str) -> bool {
+    k.chars().all(|c| c.is_ascii_alphanumeric()) && k.len() == 64
+}
+
+fn i64ton(v: i64) -> i32 {
+    if v < 0 {
+        0
+    } else if v > std::i32::MAX as i64 {
+        std::i32::MAX
+    } else {
+        v as i32
+    }
+}
+
+fn u64ton(v: u64) -> i32 {
+    if v > std::i32::MAX as u64 {
+        std::i32::MAX
+    } else {
+        v as i32
+    }
+}
+
+fn age_seconds(dt: &DateTime<Utc>) -> i32 {
+    let dur = Utc::now().signed_duration_since(*dt);
+    i64ton(dur.num_seconds())
+}
+
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct ReportSummary {
+    pub host: String,
+    pub job: String,
+    pub when: DateTime<Utc>,
+    pub status: i32,
+    pub duration_seconds: i32,
+    pub age_seconds: i32,
+    pub truncated: bool,
+    pub outcome: ReportOutcome,
+    pub rusage: Option<ResourceUsage>,
+    pub termination: Option<Termination>,
+    /**
+     * Was the most recent run of this job skipped because the previous run
+     * had not finished?  Skipped runs are otherwise left out of the summary,
+     * so that they do not hide the result of the last run that did something.
+     */
+    pub skipped: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ReportRun {
+    pub time: DateTime<Utc>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub duration_millis: Option<u64>,
+    pub status: Option<i32>,
+    pub sealed: bool,
+    pub truncated: bool,
+    pub outcome: Option<ReportOutcome>,
+}
+
+/**
+ * The resources consumed by a job and all of its descendants, as reported by
+ * the operating system once the job has exited.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ResourceUsage {
+    pub user_cpu_millis: u64,
+    pub system_cpu_millis: u64,
+    pub max_rss_kib: u64,
+    pub block_input: u64,
+    pub block_output: u64,
+    pub voluntary_context_switches: u64,
+    pub involuntary_context_switches: u64,
+}
+
+/**
+ * How the process for a job ended, as seen by the client that ran it.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Termination {
+    /**
+     * The process exited with a status code.
+     */
+    Exited { code: i32 },
+    /**
+     * The process was killed by a signal.
+     */
+    Signalled { signal: i32 },
+    /**
+     * The process was killed by a signal, and dumped core.
+     */
+    CoreDumped { signal: i32 },
+    /**
+     * The client was unable to find out how the process ended.
+     */
+    WaitError { message: String },
+}
+
+/**
+ * How a job came to an end.
+ */
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportOutcome {
+    /**
+     * The job exited of its own accord, successfully or otherwise.
+     */
+    #[default]
+    Completed,
+    /**
+     * The job was killed because it ran for longer than its timeout.
+     */
+    TimedOut,
+    /**
+     * The job was not started, because a previous run had not finished.
+     */
+    Skipped,
+    /**
+     * We stopped hearing from the client while the job was running, so we
+     * do not know how it ended.
+     */
+    Abandoned,
+}
+
+/**
+ * The schedule on which we expect a particular job to run.  Either a cron
+ * expression or a maximum interval between runs must be provided.  A job is
+ * considered overdue once the grace period has elapsed after the time at
+ * which we next expected it to start.
+ */
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Schedule {
+    /**
+     * A cron expression, evaluated in UTC.  The traditional five field form
+     * is accepted, as is the extended form that includes seconds.
+     */
+    #[serde(default)]
+    pub cron: Option<String>,
+    /**
+     * The maximum expected interval between the start of each run.
+     */
+    #[serde(default)]
+    pub interval_seconds: Option<u64>,
+    #[serde(default)]
+    pub grace_seconds: u64,
+}
+
+impl Schedule {
+    fn parse_cron(cron: &str) -> Result<cron::Schedule> {
+        /*
+         * The cron crate requires a seconds field, which is not something
+         * anybody writes in a crontab.
+         */
+        let expr = if cron.split_whitespace().count() == 5 {
+            format!("0 {}", cron)
+        } else {
+            cron.to_string()
+        };
+
+        cron::Schedule::from_str(&expr)
+            .map_err(|e| anyhow!("invalid cron expression: {}", e))
+    }
+
+    pub fn check(&self) -> Result<()> {
+        match (&self.cron, &self.interval_seconds) {
+            (Some(cron), None) => {
+                Self::parse_cron(cron)?;
+            }
+            (None, Some(interval)) => {
+                if *interval == 0 {
+                    bail!("interval must be at least one second");
+                }
+            }
+            _ => bail!("specify exactly one of a cron expression or interval"),
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Determine when we next expect the job to start, given the time it last
+     * started.
+     */
+    pub fn next_after(&self, last: &DateTime<Utc>) -> Result<DateTime<Utc>> {
+        if let Some(cron) = &self.cron {
+            /*
+             * A job may begin a moment before the time at which it was
+             * scheduled if the clocks on the two systems disagree slightly.
+             * Don't count that run as belonging to the previous slot.
+             */
+            let from = *last + chrono::Duration::seconds(60);
+            if let Some(next) = Self::parse_cron(cron)?.after(&from).next() {
+                Ok(next)
+            } else {
+                bail!("cron expression {:?} has no future times", cron);
+            }
+        } else if let Some(interval) = self.interval_seconds {
+            Ok(*last + chrono::Duration::seconds(i64::try_from(interval)?))
+        } else {
+            bail!("schedule has neither cron expression nor interval");
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduleFile {
+    host: String,
+    job: String,
+    time_create: DateTime<Utc>,
+    schedule: Schedule,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ScheduleStatus {
+    pub host: String,
+    pub job: String,
+    pub schedule: Schedule,
+    /**
+     * The start time of the most recent run of this job, if there has been
+     * one.
+     */
+    pub last_run: Option<DateTime<Utc>>,
+    /**
+     * The time by which the next run must begin before the job is considered
+     * overdue.
+     */
+    pub deadline: DateTime<Utc>,
+    pub overdue: bool,
+}
+
+/**
+ * The output of a report is not stored in the report file itself, but
+ * appended to a log of newline-delimited JSON records next to it, so that
+ * each new record does not require us to rewrite everything that came before.
+ * Once the output exceeds half of the configured limits, further records are
+ * appended to a second "tail" log, which is periodically rewritten to discard
+ * older records.  The tail log begins with a description of what has been
+ * discarded.
+ */
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum OutputLogEntry {
+    Record(OutputRecord),
+    Truncation(OutputTruncation),
+}
+
+/**
+ * The number of recent records we remember for each report, so that a record
+ * without a sequence number that the client sends again because it did not
+ * see our response is not stored twice.
+ */
+const RECENT_RECORDS: usize = 16;
+
+/**
+ * For each report that is still receiving output, we keep a summary of the
+ * output logs so that we need not read them to append another record.
+ */
+#[derive(Default)]
+struct OpenOutput {
+    head_lines: usize,
+    head_bytes: usize,
+    tail_lines: usize,
+    tail_bytes: usize,
+    recent: VecDeque<OutputRecord>,
+    seen: SeenSeqs,
+}
+
+/**
+ * The set of sequence numbers we have stored for a report.  Records generally
+ * arrive in order, so we keep the point below which we have seen every
+ * number, and only those above it individually.
+ */
+#[derive(Default)]
+struct SeenSeqs {
+    below: u64,
+    above: BTreeSet<u64>,
+}
+
+impl SeenSeqs {
+    /**
+     * Returns false if we have seen this sequence number already.
+     */
+    fn insert(&mut self, seq: u64) -> bool {
+        if seq < self.below || !self.above.insert(seq) {
+            return false;
+        }
+        while self.above.remove(&self.below) {
+            self.below += 1;
+        }
+        true
+    }
+
+    /**
+     * Note that every sequence number below this one has been seen, even if
+     * we did not keep the records.
+     */
+    fn skip_to(&mut self, seq: u64) {
+        if seq > self.below {
+            self.below = seq;
+            self.above = self.above.split_off(&seq);
+            while self.above.remove(&self.below) {
+                self.below += 1;
+            }
+        }
+    }
+}
+
+impl OpenOutput {
+    fn remember(&mut self, rec: &OutputRecord) {
+        if let Some(seq) = rec.seq {
+            self.seen.insert(seq);
+            return;
+        }
+        if self.recent.len() >= RECENT_RECORDS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(rec.clone());
+    }
+}
+
+fn read_log(p: &Path) -> Result<Vec<OutputLogEntry>> {
+    let data = match std::fs::read(p) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => bail!("read log {}: {:?}", p.display(), e),
+    };
+
+    /*
+     * If we crashed while appending to the log, the last line may be
+     * incomplete.  Skip anything we cannot parse.
+     */
+    Ok(data
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.is_empty())
+        .filter_map(|l| serde_json::from_slice(l).ok())
+        .collect())
+}
+
+fn append_log(p: &Path, rec: &OutputRecord) -> Result<()> {
+    let mut buf = serde_json::to_vec(rec)?;
+    buf.push(b'\n');
+
+    /*
+     * Write the whole line at once, so that a crash leaves at most one
+     * incomplete line at the end of the log.
+     */
+    let mut f = OpenOptions::new().create(true).append(true).open(p)?;
+    f.write_all(&buf)?;
+    Ok(())
+}
+
+/**
+ * Remove any incomplete line from the end of a log, so that the next record
+ * we append does not end up on the same line.
+ */
+fn repair_log(p: &Path) -> Result<()> {
+    let data = match std::fs::read(p) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => bail!("read log {}: {:?}", p.display(), e),
+    };
+
+    if data.is_empty() || data.ends_with(b"\n") {
+        return Ok(());
+    }
+
+    let len = data.iter().rposition(|b| *b == b'\n').map(|i| i + 1);
+    OpenOptions::new()
+        .write(true)
+        .open(p)?
+        .set_len(len.unwrap_or(0) as u64)?;
+    Ok(())
+}
+
+/**
+ * A report that has been started, but not yet sealed.
+ */
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct RunningReport {
+    pub host: String,
+    pub job: String,
+    pub time: DateTime<Utc>,
+    pub start_time: DateTime<Utc>,
+    pub pid: u32,
+    pub running_seconds: i32,
+    /**
+     * When we last heard from the client, either when the report was started
+     * or through a heartbeat.
+     */
+    pub last_seen: DateTime<Utc>,
+    /**
+     * The number of other instances of a job with the same name, on any
+     * host, that are also running.
+     */
+    pub overlapping: u32,
+}
+
+#[derive(Clone)]
+struct RunningEntry {
+    start_time: DateTime<Utc>,
+    pid: u32,
+    last_seen: DateTime<Utc>,
+    heartbeat: bool,
+}
+
+impl RunningEntry {
+    fn new(post: &PostFile) -> RunningEntry {
+        RunningEntry {
+            start_time: post.time_start,
+            pid: post.report_pid,
+            last_seen: post.last_heartbeat.unwrap_or(post.report_time),
+            heartbeat: post.last_heartbeat.is_some(),
+        }
+    }
+}
+
+/**
+ * Finding the reports that are still running would otherwise require us to
+ * load every report, so we do that once and then keep track of reports as
+ * they are stored.  The index is keyed on host, job, and report time.
+ */
+type RunningIndex = HashMap<(String, String, i64), RunningEntry>;
+
+pub struct ReportStore {
+    dir: PathBuf,
+    log: Logger,
+    open: Mutex<HashMap<PathBuf, OpenOutput>>,
+    running: Mutex<Option<RunningIndex>>,
+}
+
+impl ReportStore {
+    pub fn new<P: AsRef<Path>>(log: Logger, dir: P) -> Result<ReportStore> {
+        Ok(ReportStore {
+            log,
+            dir: dir.as_ref().to_path_buf(),
+            open: Default::default(),
+            running: Default::default(),
+        })
+    }
+
+    fn list_reports(
+        &self,
+        host: &str,
+        job: &str,
+        year: u32,
+        month: u32,
+        day: u32,
+    ) -> Result<Vec<i64>> {
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        targ.push(host);
+        targ.push(job);
+        targ.push(&format!("{:04}", year));
+        targ.push(&format!("{:02}", month));
+        targ.push(&format!("{:02}", day));
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&targ).context("report reports")?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_file() {
+                continue;
+            }
+
+            if let Some(n) = ent.file_name().to_str() {
+                if !n.ends_with(".json") {
+                    continue;
+                }
+
+                if let Ok(num) = n.trim_end_matches(".json").parse::<i64>() {
+                    if !out.contains(&num) {
+                        out.push(num);
+                    }
+                }
+            }
+        }
+
+        out.sort();
+        out.reverse();
+        Ok(out)
+    }
+
+    fn list_days(
+        &self,
+        host: &str,
+        job: &str,
+        year: u32,
+        month: u32,
+    ) -> Result<Vec<u32>> {
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        targ.push(host);
+        targ.push(job);
+        targ.push(&format!("{:04}", year));
+        targ.push(&format!("{:02}", month));
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&targ).context("report days")?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(n) = ent.file_name().to_str() {
+                if let Ok(num) = n.parse::<u32>() {
+                    if !out.contains(&num) {
+                        out.push(num);
+                    }
+                }
+            }
+        }
+
+        out.sort();
+        out.reverse();
+        Ok(out)
+    }
+
+    fn list_months(
+        &self,
+        host: &str,
+        job: &str,
+        year: u32,
+    ) -> Result<Vec<u32>> {
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        targ.push(host);
+        targ.push(job);
+        targ.push(&format!("{:04}", year));
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&targ).context("report months")?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(n) = ent.file_name().to_str() {
+                if let Ok(num) = n.parse::<u32>() {
+                    if !out.contains(&num) {
+                        out.push(num);
+                    }
+                }
+            }
+        }
+
+        out.sort();
+        out.reverse();
+        Ok(out)
+    }
+
+    fn list_years(&self, host: &str, job: &str) -> Result<Vec<u32>> {
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        targ.push(host);
+        targ.push(job);
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&targ).context("report years")?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(n) = ent.file_name().to_str() {
+                if let Ok(num) = n.parse::<u32>() {
+                    if !out.contains(&num) {
+                        out.push(num);
+                    }
+                }
+            }
+        }
+
+        out.sort();
+        out.reverse();
+        Ok(out)
+    }
+
+    fn list_jobs(&self, host: &str) -> Result<Vec<String>> {
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        targ.push(host);
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&targ).context("report jobs")?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(n) = ent.file_name().to_str() {
+                /*
+                 * NB: directory entry names should be unique, so we don't check
+                 * here.
+                 */
+                out.push(n.to_string());
+            }
+        }
+
+        out.sort();
+        Ok(out)
+    }
+
+    fn list_hosts(&self) -> Result<Vec<String>> {
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&targ).context("report hosts")?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(n) = ent.file_name().to_str() {
+                /*
+                 * NB: directory entry names should be unique, so we don't check
+                 * here.
+                 */
+                out.push(n.to_string());
+            }
+        }
+
+        out.sort();
+        Ok(out)
+    }
+
+    fn reportpath(
+        &self,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+    ) -> Result<PathBuf> {
+        if !name_ok(host) || !name_ok(job) {
+            bail!("invalid host or job name");
+        }
+
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        targ.push(host);
+        targ.push(job);
+        targ.push(time.format("%Y").to_string());
+        targ.push(time.format("%m").to_string());
+        targ.push(time.format("%d").to_string());
+        targ.push(format!("{}.json", time.timestamp_millis()));
+
+        Ok(targ)
+    }
+
+    pub fn summary(&self, perjob: usize) -> Result<Vec<ReportSummary>> {
+        let mut out = Vec::new();
+
+        for host in self.list_hosts()?.iter() {
+            'job: for job in self.list_jobs(host)?.iter() {
+                let mut c = 0usize;
+                let mut newest: Option<ReportSummary> = None;
+                for y in self.list_years(host, job)?.iter() {
+                    if c >= perjob {
+                        continue 'job;
+                    }
+
+                    for m in self.list_months(host, job, *y)?.iter() {
+                        if c >= perjob {
+                            continue 'job;
+                        }
+
+                        for d in self.list_days(host, job, *y, *m)?.iter() {
+                            if c >= perjob {
+                                continue 'job;
+                            }
+
+                            for r in
+                                self.list_reports(host, job, *y, *m, *d)?.iter()
+                            {
+                                if c >= perjob {
+                                    continue 'job;
+                                }
+
+                                let dt = Utc.timestamp_millis_opt(*r).unwrap();
+                                let t = self.reportpath(host, job, &dt)?;
+
+                                if let Ok(Some(p)) = load_file::<PostFile>(&t) {
+                                    if p.sealed {
+                                        let dur =
+                                            p.duration_seconds().unwrap_or(0);
+
+                                        let rs = ReportSummary {
+                                            host: host.to_string(),
+                                            job: job.to_string(),
+                                            age_seconds: age_seconds(&dt),
+                                            duration_seconds: dur,
+                                            when: dt,
+                                            /*
+                                             * Abandoned reports have no
+                                             * exit status.
+                                             */
+                                            status: p.status.unwrap_or(-1),
+                                            truncated: p.truncation.is_some(),
+                                            outcome: p
+                                                .outcome
+                                                .unwrap_or_default(),
+                                            rusage: p.rusage,
+                                            termination: p.termination,
+                                            skipped: newest
+                                                .as_ref()
+                                                .map(|n| n.skipped)
+                                                .unwrap_or(false),
+                                        };
+
+                                        if newest.is_none() {
+                                            newest = Some(ReportSummary {
+                                                skipped: rs.outcome
+                                                    == ReportOutcome::Skipped,
+                                                ..rs.clone()
+                                            });
+                                        }
+
+                                        if rs.outcome != ReportOutcome::Skipped
+                                        {
+                                            out.push(rs);
+                                            c += 1;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                /*
+                 * If every run we have was skipped, there is no better
+                 * result to show than the most recent skip.
+                 */
+                if c == 0 {
+                    if let Some(rs) = newest {
+                        out.push(rs);
+                    }
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /**
+     * List the reports for a particular job, newest first.  Only reports
+     * strictly older than "before" and no older than "after" are included,
+     * and at most "limit" entries are returned.  The report time is stored in
+     * the file name, so we can skip over whole directories that fall outside
+     * the requested range without loading anything.
+     */
+    pub fn history(
+        &self,
+        host: &str,
+        job: &str,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<ReportRun>> {
+        if !name_ok(host) || !name_ok(job) {
+            bail!("invalid host or job name");
+        }
+
+        let mut out = Vec::new();
+
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        targ.push(host);
+        targ.push(job);
+        if !targ.is_dir() {
+            return Ok(out);
+        }
+
+        let before_day = before.map(|t| t.date_naive());
+        let after_day = after.map(|t| t.date_naive());
+
+        for y in self.list_years(host, job)?.iter() {
+            for m in self.list_months(host, job, *y)?.iter() {
+                for d in self.list_days(host, job, *y, *m)?.iter() {
+                    let day = if let Some(day) =
+                        NaiveDate::from_ymd_opt(*y as i32, *m, *d)
+                    {
+                        day
+                    } else {
+                        continue;
+                    };
+
+                    if before_day.map(|b| day > b).unwrap_or(false) {
+                        continue;
+                    }
+                    if after_day.map(|a| day < a).unwrap_or(false) {
+                        return Ok(out);
+                    }
+
+                    for r in self.list_reports(host, job, *y, *m, *d)?.iter() {
+                        if before
+                            .map(|b| *r >= b.timestamp_millis())
+                            .unwrap_or(false)
+                        {
+                            continue;
+                        }
+                        if after
+                            .map(|a| *r < a.timestamp_millis())
+                            .unwrap_or(false)
+                        {
+                            return Ok(out);
+                        }
+
+                        let dt = Utc.timestamp_millis_opt(*r).unwrap();
+                        let t = self.reportpath(host, job, &dt)?;
+
+                        if let Ok(Some(p)) = load_file::<PostFile>(&t) {
+                            out.push(ReportRun {
+                                time: dt,
+                                start_time: p.time_start,
+                                end_time: p.time_end,
+                                duration_millis: p.duration,
+                                status: p.status,
+                                sealed: p.sealed,
+                                truncated: p.truncation.is_some(),
+                                outcome: p.outcome,
+                            });
+
+                            if out.len() >= limit {
+                                return Ok(out);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /**
+     * Remove all reports from before the specified time, optionally
+     * restricted to a particular host or job.  Reports that are still
+     * running are kept, however long ago they started.  Any directories that
+     * are emptied as a result are removed as well.  Returns the number of
+     * reports removed.
+     */
+    pub fn prune(
+        &self,
+        before: &DateTime<Utc>,
+        host: Option<&str>,
+        job: Option<&str>,
+    ) -> Result<usize> {
+        let mut count = 0;
+
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        if !targ.is_dir() {
+            return Ok(count);
+        }
+
+        let before_day = before.date_naive();
+
+        for h in self.list_hosts()?.iter() {
+            if host.map(|host| host != h).unwrap_or(false) {
+                continue;
+            }
+
+            for j in self.list_jobs(h)?.iter() {
+                if job.map(|job| job != j).unwrap_or(false) {
+                    continue;
+                }
+
+                for y in self.list_years(h, j)?.iter() {
+                    for m in self.list_months(h, j, *y)?.iter() {
+                        for d in self.list_days(h, j, *y, *m)?.iter() {
+                            match NaiveDate::from_ymd_opt(*y as i32, *m, *d) {
+                                Some(day) if day <= before_day => (),
+                                _ => continue,
+                            }
+
+                            for r in self.list_reports(h, j, *y, *m, *d)?.iter()
+                            {
+                                if *r >= before.timestamp_millis() {
+                                    continue;
+                                }
+
+                                let dt = Utc.timestamp_millis_opt(*r).unwrap();
+                                let t = self.reportpath(h, j, &dt)?;
+
+                                /*
+                                 * A report we cannot read is of no use to
+                                 * anybody, so it is removed as well.
+                                 */
+                                match load_file::<PostFile>(&t) {
+                                    Ok(Some(f)) if !f.sealed => continue,
+                                    Ok(None) => continue,
+                                    _ => (),
+                                }
+
+                                debug!(self.log, "pruning {}", t.display());
+                                std::fs::remove_file(&t)?;
+                                let (head, tail) =
+                                    self.outputpaths(h, j, &dt)?;
+                                for p in [&head, &tail] {
+                                    match std::fs::remove_file(p) {
+                                        Err(e)
+                                            if e.kind()
+                                                != ErrorKind::NotFound =>
+                                        {
+                                            return Err(e.into());
+                                        }
+                                        _ => (),
+                                    }
+                                }
+                                self.open.lock().unwrap().remove(&head);
+                                if let Some(idx) =
+                                    self.running.lock().unwrap().as_mut()
+                                {
+                                    idx.remove(&(
+                                        h.to_string(),
+                                        j.to_string(),
+                                        *r,
+                                    ));
+                                }
+                                count += 1;
+                            }
+
+                            /*
+                             * Try to remove the day, month, and year
+                             * directories.  This will fail, harmlessly, if
+                             * they still contain anything.
+                             */
+                            let mut dir = targ.clone();
+                            dir.push(h);
+                            dir.push(j);
+                            dir.push(format!("{:04}", y));
+                            dir.push(format!("{:02}", m));
+                            dir.push(format!("{:02}", d));
+                            for _ in 0..3 {
+                                if std::fs::remove_dir(&dir).is_err() {
+                                    break;
+                                }
+                                dir.pop();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        info!(self.log, "pruned {} reports from before {}", count, before);
+        Ok(count)
+    }
+
+    fn schedulepath(&self, host: &str, job: &str) -> Result<PathBuf> {
+        if !name_ok(host) || !name_ok(job) {
+            bail!("invalid host or job name");
+        }
+
+        let mut targ = self.dir.clone();
+        targ.push("schedules");
+        targ.push(host);
+        targ.push(format!("{}.json", job));
+
+        Ok(targ)
+    }
+
+    fn list_schedules(&self) -> Result<Vec<ScheduleFile>> {
+        let mut out = Vec::new();
+
+        let mut targ = self.dir.clone();
+        targ.push("schedules");
+        if !targ.is_dir() {
+            return Ok(out);
+        }
+
+        let mut dir = std::fs::read_dir(&targ).context("schedule hosts")?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+
+            let mut jobs =
+                std::fs::read_dir(ent.path()).context("schedules")?;
+            while let Some(ent) = jobs.next().transpose()? {
+                if !ent.file_type()?.is_file() {
+                    continue;
+                }
+
+                if let Some(n) = ent.file_name().to_str() {
+                    if !n.ends_with(".json") {
+                        continue;
+                    }
+
+                    if let Some(f) = load_file::<ScheduleFile>(&ent.path())? {
+                        out.push(f);
+                    }
+                }
+            }
+        }
+
+        out.sort_by(|a, b| (&a.host, &a.job).cmp(&(&b.host, &b.job)));
+        Ok(out)
+    }
+
+    /**
+     * Determine the state of every job for which we have an expected
+     * schedule.  A job that has never reported in is measured from the time
+     * the schedule was created.
+     */
+    pub fn schedule_status(&self) -> Result<Vec<ScheduleStatus>> {
+        let now = Utc::now();
+        let mut out = Vec::new();
+
+        for f in self.list_schedules()? {
+            let last_run = self
+                .history(&f.host, &f.job, None, None, 1)?
+                .first()
+                .map(|r| r.start_time);
+
+            let next =
+                f.schedule.next_after(&last_run.unwrap_or(f.time_create))?;
+            let grace = i64::try_from(f.schedule.grace_seconds)?;
+            let deadline = next + chrono::Duration::seconds(grace);
+
+            out.push(ScheduleStatus {
+                host: f.host,
+                job: f.job,
+                schedule: f.schedule,
+                last_run,
+                deadline,
+                overdue: now > deadline,
+            });
+        }
+
+        Ok(out)
+    }
+
+    pub fn set_schedule(
+        &self,
+        host: &str,
+        job: &str,
+        schedule: &Schedule,
+    ) -> Result<()> {
+        schedule.check()?;
+
+        let targ = self.schedulepath(host, job)?;
+        let dir = targ.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+
+        /*
+         * Preserve the creation time of an existing schedule, so that
+         * adjusting the grace period of a job that has never run does not
+         * reset the clock.
+         */
+        let time_create = load_file::<ScheduleFile>(&targ)?
+            .map(|f| f.time_create)
+            .unwrap_or_else(Utc::now);
+
+        store_file(
+            &targ,
+            &ScheduleFile {
+                host: host.to_string(),
+                job: job.to_string(),
+                time_create,
+                schedule: schedule.clone(),
+            },
+            false,
+        )
+    }
+
+    pub fn remove_schedule(&self, host: &str, job: &str) -> Result<bool> {
+        let targ = self.schedulepath(host, job)?;
+
+        match std::fs::remove_file(&targ) {
+            Ok(()) => {
+                /*
+                 * Remove the host directory if this was the last schedule.
+                 */
+                std::fs::remove_dir(targ.parent().unwrap()).ok();
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn outputpaths(
+        &self,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+    ) -> Result<(PathBuf, PathBuf)> {
+        let targ = self.reportpath(host, job, time)?;
+
+        Ok((
+            targ.with_extension("output.ndjson"),
+            targ.with_extension("tail.ndjson"),
+        ))
+    }
+
+    /**
+     * Load a report, including all of its output.
+     */
+    pub fn load(
+        &self,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+    ) -> Result<Option<PostFile>> {
+        let mut pf = if let Some(pf) = self.load_meta(host, job, time)? {
+            pf
+        } else {
+            return Ok(None);
+        };
+
+        let (head, tail) = self.outputpaths(host, job, time)?;
+        for e in read_log(&head)? {
+            if let OutputLogEntry::Record(rec) = e {
+                pf.output.push(rec);
+            }
+        }
+        for e in read_log(&tail)? {
+            match e {
+                OutputLogEntry::Record(rec) => pf.output_tail.push(rec),
+                OutputLogEntry::Truncation(t) => {
+                    if pf.truncation.is_none() {
+                        pf.truncation = Some(t);
+                    }
+                }
+            }
+        }
+        if let Some(t) = pf.truncation.as_mut() {
+            t.after = pf.output.len() as u64;
+        }
+
+        /*
+         * Records may arrive out of order if the client had to send some of
+         * them again.  The sort is stable, so records without a sequence
+         * number remain in the order they arrived.
+         */
+        pf.output.sort_by_key(|rec| rec.seq);
+        pf.output_tail.sort_by_key(|rec| rec.seq);
+
+        Ok(Some(pf))
+    }
+
+    /**
+     * Load a report, without the output records.
+     */
+    pub fn load_meta(
+        &self,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+    ) -> Result<Option<PostFile>> {
+        let targ = self.reportpath(host, job, time)?;
+        load_file(&targ)
+    }
+
+    fn open_output(&self, head: &Path, tail: &Path) -> Result<OpenOutput> {
+        repair_log(head)?;
+        repair_log(tail)?;
+
+        let mut oo = OpenOutput::default();
+        for e in read_log(head)? {
+            if let OutputLogEntry::Record(rec) = e {
+                oo.head_lines += 1;
+                oo.head_bytes += rec.msg.len();
+                oo.remember(&rec);
+            }
+        }
+        let mut truncated = false;
+        let mut first = None;
+        for e in read_log(tail)? {
+            match e {
+                OutputLogEntry::Record(rec) => {
+                    oo.tail_lines += 1;
+                    oo.tail_bytes += rec.msg.len();
+                    oo.remember(&rec);
+                    first = first.or(rec.seq);
+                }
+                OutputLogEntry::Truncation(_) => truncated = true,
+            }
+        }
+
+        /*
+         * Records discarded from the tail log came before those that remain,
+         * so if the client sends them again they must not be stored.
+         */
+        if let (true, Some(first)) = (truncated, first) {
+            oo.seen.skip_to(first);
+        }
+
+        Ok(oo)
+    }
+
+    /**
+     * Discard records from the start of the tail log until it fits within
+     * the limits, recording what was discarded at the start of the log.
+     * Returns the size of what remains, and a description of everything
+     * discarded so far.
+     */
+    fn compact_tail(
+        &self,
+        tail: &Path,
+        after: usize,
+        max_lines: usize,
+        max_bytes: usize,
+    ) -> Result<(usize, usize, Option<OutputTruncation>)> {
+        let mut trunc = None;
+        let mut recs = Vec::new();
+        for e in read_log(tail)? {
+            match e {
+                OutputLogEntry::Record(rec) => recs.push(rec),
+                OutputLogEntry::Truncation(t) => trunc = Some(t),
+            }
+        }
+
+        let mut bytes: usize = recs.iter().map(|r| r.msg.len()).sum();
+        let mut skip = 0;
+        while recs.len() - skip > max_lines || bytes > max_bytes {
+            let len = recs[skip].msg.len();
+            bytes -= len;
+            skip += 1;
+
+            let t = trunc.get_or_insert(OutputTruncation {
+                after: after as u64,
+                omitted_lines: 0,
+                omitted_bytes: 0,
+            });
+            t.omitted_lines += 1;
+            t.omitted_bytes += len as u64;
+        }
+
+        if skip > 0 {
+            /*
+             * Write the new tail log to a temporary file and rename it into
+             * place, so that a crash leaves us with either the old or the new
+             * log.
+             */
+            let mut tmp = tail.as_os_str().to_os_string();
+            tmp.push(".tmp");
+            let tmp = PathBuf::from(tmp);
+
+            let f = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp)?;
+            let mut bw = BufWriter::new(f);
+            if let Some(t) = &trunc {
+                serde_json::to_writer(&mut bw, t)?;
+                bw.write_all(b"\n")?;
+            }
+            for rec in recs.iter().skip(skip) {
+                serde_json::to_writer(&mut bw, rec)?;
+                bw.write_all(b"\n")?;
+            }
+            bw.flush()?;
+            bw.get_ref().sync_all()?;
+
+            std::fs::rename(&tmp, tail)?;
+        }
+
+        Ok((recs.len() - skip, bytes, trunc))
+    }
+
+    /**
+     * Append an output record to a report.  Returns false if we have already
+     * stored a record with the same sequence number, in which case it is not
+     * stored again.  Records from older clients do not have a sequence
+     * number, so we instead look for an identical record among those seen
+     * recently.
+     */
+    pub fn append_output(
+        &self,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+        rec: &OutputRecord,
+        limits: &OutputLimits,
+    ) -> Result<bool> {
+        let (head, tail) = self.outputpaths(host, job, time)?;
+        let max_lines = limits.max_lines / 2;
+        let max_bytes = limits.max_bytes / 2;
+
+        let mut open = self.open.lock().unwrap();
+        if !open.contains_key(&head) {
+            let oo = self.open_output(&head, &tail)?;
+            open.insert(head.clone(), oo);
+        }
+        let oo = open.get_mut(&head).unwrap();
+
+        let new = if let Some(seq) = rec.seq {
+            oo.seen.insert(seq)
+        } else {
+            !oo.recent.contains(rec)
+        };
+        if !new {
+            return Ok(false);
+        }
+
+        let len = rec.msg.len();
+        if oo.tail_lines == 0
+            && oo.head_lines < max_lines
+            && oo.head_bytes + len <= max_bytes
+        {
+            append_log(&head, rec)?;
+            oo.head_lines += 1;
+            oo.head_bytes += len;
+        } else {
+            append_log(&tail, rec)?;
+            oo.tail_lines += 1;
+            oo.tail_bytes += len;
+
+            /*
+             * Rewriting the tail log for every record would be no better than
+             * rewriting the whole report, so allow it to grow to twice the
+             * limit before we discard anything.
+             */
+            if oo.tail_lines > 2 * max_lines || oo.tail_bytes > 2 * max_bytes {
+                let (lines, bytes, _) = self.compact_tail(
+                    &tail,
+                    oo.head_lines,
+                    max_lines,
+                    max_bytes,
+                )?;
+                oo.tail_lines = lines;
+                oo.tail_bytes = bytes;
+            }
+        }
+        if rec.seq.is_none() {
+            oo.remember(rec);
+        }
+
+        Ok(true)
+    }
+
+    /**
+     * Called when a report is complete, to trim the output to the limits and
+     * forget what we know about the open report.  Returns a description of
+     * any output that was discarded.
+     */
+    pub fn finish_output(
+        &self,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+        limits: &OutputLimits,
+    ) -> Result<Option<OutputTruncation>> {
+        let (head, tail) = self.outputpaths(host, job, time)?;
+
+        let oo = self.open.lock().unwrap().remove(&head);
+        let after = if let Some(oo) = oo {
+            oo.head_lines
+        } else {
+            read_log(&head)?.len()
+        };
+
+        let (_, _, trunc) = self.compact_tail(
+            &tail,
+            after,
+            limits.max_lines / 2,
+            limits.max_bytes / 2,
+        )?;
+        Ok(trunc)
+    }
+
+    pub fn store(
+        &self,
+        host: &str,
+        job: &str,
+        time: &DateTime<Utc>,
+        post: &PostFile,
+    ) -> Result<()> {
+        let targ = self.reportpath(host, job, time)?;
+
+        let dir = targ.parent().unwrap();
+        debug!(self.log, "creating report directory: {}", dir.display());
+        std::fs::create_dir_all(dir)?;
+
+        store_file(&targ, post, false)?;
+
+        /*
+         * The report itself has been stored, so a problem with the index is
+         * not a reason to fail the request.
+         */
+        let mut running = match self.running_index() {
+            Ok(running) => running,
+            Err(e) => {
+                error!(self.log, "could not load running reports: {:?}", e);
+                return Ok(());
+            }
+        };
+        let idx = running.as_mut().unwrap();
+        let key = (host.to_string(), job.to_string(), time.timestamp_millis());
+        let changed = if post.sealed {
+            idx.remove(&key).is_some()
+        } else {
+            idx.insert(key, RunningEntry::new(post)).is_none()
+        };
+        if changed {
+            self.save_running(idx);
+        }
+
+        Ok(())
+    }
+
+    fn runningpath(&self) -> PathBuf {
+        self.dir.join("running.json")
+    }
+
+    /**
+     * Lock the running index, loading it first if need be.
+     */
+    fn running_index(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, Option<RunningIndex>>> {
+        let mut running = self.running.lock().unwrap();
+        if running.is_none() {
+            *running = Some(self.load_running()?);
+        }
+        Ok(running)
+    }
+
+    /**
+     * The reports in the running index are listed in "running.json", so that
+     * we need not look at every report ever stored to rebuild the index when
+     * the server starts.  Only the list is stored; everything else is loaded
+     * from the reports themselves.  If there is no list, as when upgrading
+     * from a version without one, we must scan everything once.
+     */
+    fn load_running(&self) -> Result<RunningIndex> {
+        let keys = match load_file::<Vec<(String, String, i64)>>(
+            &self.runningpath(),
+        ) {
+            Ok(Some(keys)) => keys,
+            Ok(None) => {
+                let idx = self.scan_running()?;
+                self.save_running(&idx);
+                return Ok(idx);
+            }
+            Err(e) => {
+                warn!(self.log, "could not load running reports: {:?}", e);
+                let idx = self.scan_running()?;
+                self.save_running(&idx);
+                return Ok(idx);
+            }
+        };
+
+        /*
+         * The list may include reports that have since been removed, so we
+         * check each one.
+         */
+        let mut idx = RunningIndex::new();
+        for (host, job, time) in keys {
+            let dt = if let Some(dt) = Utc.timestamp_millis_opt(time).single() {
+                dt
+            } else {
+                continue;
+            };
+
+            if let Ok(Some(p)) = self.load_meta(&host, &job, &dt) {
+                if !p.sealed {
+                    idx.insert((host, job, time), RunningEntry::new(&p));
+                }
+            }
+        }
+
+        info!(self.log, "loaded {} running reports", idx.len());
+        Ok(idx)
+    }
+
+    fn save_running(&self, idx: &RunningIndex) {
+        let keys = idx.keys().collect::<Vec<_>>();
+        if let Err(e) = store_file(&self.runningpath(), &keys, false) {
+            /*
+             * A stale list would hide running reports after a restart, so
+             * remove it and fall back to a full scan.
+             */
+            warn!(self.log, "could not store running reports: {:?}", e);
+            if let Err(e) = std::fs::remove_file(self.runningpath()) {
+                if e.kind() != ErrorKind::NotFound {
+                    error!(self.log, "could not remove running list: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn scan_running(&self) -> Result<RunningIndex> {
+        let mut idx = RunningIndex::new();
+
+        let mut targ = self.dir.clone();
+        targ.push("reports");
+        if !targ.is_dir() {
+            return Ok(idx);
+        }
+
+        for h in self.list_hosts()?.iter() {
+            for j in self.list_jobs(h)?.iter() {
+                for y in self.list_years(h, j)?.iter() {
+                    for m in self.list_months(h, j, *y)?.iter() {
+                        for d in self.list_days(h, j, *y, *m)?.iter() {
+                            for r in self.list_reports(h, j, *y, *m, *d)?.iter()
+                            {
+                                let dt = Utc.timestamp_millis_opt(*r).unwrap();
+                                let t = self.reportpath(h, j, &dt)?;
+
+                                if let Ok(Some(p)) = load_file::<PostFile>(&t) {
+                                    if !p.sealed {
+                                        idx.insert(
+                                            (h.to_string(), j.to_string(), *r),
+                                            RunningEntry::new(&p),
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        info!(self.log, "found {} running reports", idx.len());
+        Ok(idx)
+    }
+
+    /**
+     * List the reports that have been started but not yet sealed, oldest
+     * first.
+     */
+    pub fn running(&self) -> Result<Vec<RunningReport>> {
+        let running = self.running_index()?;
+        let idx = running.as_ref().unwrap();
+
+        let mut perjob: HashMap<&str, u32> = HashMap::new();
+        for (_, job, _) in idx.keys() {
+            *perjob.entry(job.as_str()).or_default() += 1;
+        }
+
+        let mut out = idx
+            .iter()
+            .map(|((host, job, time), e)| RunningReport {
+                host: host.to_string(),
+                job: job.to_string(),
+                time: Utc.timestamp_millis_opt(*time).unwrap(),
+                start_time: e.start_time,
+                pid: e.pid,
+                running_seconds: age_seconds(&e.start_time),
+                last_seen: e.last_seen,
+                overlapping: perjob[job.as_str()] - 1,
+            })
+            .collect::<Vec<_>>();
+        out.sort_by(|a, b| {
+            a.start_time
+                .cmp(&b.start_time)
+                .then_with(|| a.host.cmp(&b.host))
+                .then_with(|| a.job.cmp(&b.job))
+        });
+
+        Ok(out)
+    }
+
+    /**
+     * Seal any running report from which we have not heard since "before",
+     * marking it as abandoned.  Older clients do not send heartbeats, and we
+     * cannot tell a long silent job from a dead client, so a report is only
+     * abandoned once its client has sent at least one heartbeat.  Returns the
+     * reports that were abandoned.
+     */
+    pub fn abandon(
+        &self,
+        before: &DateTime<Utc>,
+        limits: &OutputLimits,
+    ) -> Result<Vec<(String, String, DateTime<Utc>)>> {
+        let mut out = Vec::new();
+
+        let stale = {
+            self.running_index()?
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|(_, e)| e.heartbeat && e.last_seen < *before)
+                .map(|((host, job, time), e)| {
+                    (
+                        host.to_string(),
+                        job.to_string(),
+                        Utc.timestamp_millis_opt(*time).unwrap(),
+                        e.last_seen,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (host, job, time, last_seen) in stale {
+            let mut f = if let Some(f) = self.load_meta(&host, &job, &time)? {
+                f
+            } else {
+                continue;
+            };
+            if f.sealed {
+                continue;
+            }
+
+            info!(
+                self.log,
+                "abandoning report {}/{}/{}; last seen {}",
+                host,
+                job,
+                time.timestamp_millis(),
+                last_seen,
+            );
+
+            f.sealed = true;
+            f.outcome = Some(ReportOutcome::Abandoned);
+            f.time_end = Some(last_seen);
+            /*
+             * The start time comes from the clock on the client, which may
+             * be ahead of ours.
+             */
+            f.duration = Some(
+                last_seen
+                    .signed_duration_since(f.time_start)
+                    .num_milliseconds()
+                    .max(0) as u64,
+            );
+            if let Some(t) = self.finish_output(&host, &job, &time, limits)? {
+                f.truncation = Some(t);
+            }
+            self.store(&host, &job, &time, &f)?;
+
+            out.push((host, job, time));
+        }
+
+        Ok(out)
+    }
+}
+
+fn key_hash(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+#[derive(Clone)]
+struct KeyIndexEntry {
+    host: String,
+    key_id: String,
+    time_expire: Option<DateTime<Utc>>,
+    global_view: bool,
+    admin: bool,
+    hash: Option<KeyHash>,
+    /**
+     * The key is stored in plain text, or without an index hash, and the
+     * file should be rewritten once we have seen it used.
+     */
+    upgrade: bool,
+}
+
+/**
+ * Authenticating a request would otherwise require us to load every key file
+ * in the "keys" directory, so we keep an index of the keys in memory, keyed on
+ * the SHA-256 hash of the key itself.  The index is rebuilt whenever the
+ * modification time of the directory changes, which happens whenever a key
+ * file is created, renamed, or removed.  Files edited in place will not be
+ * noticed until an explicit reload, through the API or when the server
+ * receives SIGHUP.
+ *
+ * Salted hashes stored before we kept an index hash alongside them cannot be
+ * placed in the index until we have seen the key itself, so the first request
+ * with such a key must check each of them in turn.  Once a key has been
+ * verified that way, its file is upgraded.
+ */
+#[derive(Default)]
+struct KeyIndex {
+    mtime: Option<SystemTime>,
+    entries: HashMap<[u8; 32], KeyIndexEntry>,
+    salted: Vec<(KeyHash, KeyIndexEntry)>,
+    duplicates: HashMap<[u8; 32], (String, String)>,
+}
+
+impl KeyIndex {
+    /**
+     * Find a host already using the key with this index hash.  Salted hashes
+     * without an index can only be checked if we have the key itself.
+     */
+    fn key_user(&self, hash: &[u8; 32], key: Option<&str>) -> Option<&str> {
+        if let Some(e) = self.entries.get(hash) {
+            return Some(&e.host);
+        }
+        if let Some((a, _)) = self.duplicates.get(hash) {
+            return Some(a);
+        }
+        if let Some(key) = key {
+            if let Some((_, e)) =
+                self.salted.iter().find(|(kh, _)| kh.matches(key))
+            {
+                return Some(&e.host);
+            }
+        }
+        None
+    }
+}
+
+pub struct KeyStore {
+    dir: PathBuf,
+    log: Logger,
+    index: Mutex<KeyIndex>,
+}
+
+impl KeyStore {
+    pub fn new<P: AsRef<Path>>(log: Logger, dir: P) -> Result<KeyStore> {
+        let ks = KeyStore {
+            log,
+            dir: dir.as_ref().to_path_buf(),
+            index: Mutex::new(KeyIndex::default()),
+        };
+        ks.reload()?;
+        Ok(ks)
+    }
+
+    fn load_index(&self) -> Result<KeyIndex> {
+        let kdir = self.keypath("keys", None)?;
+
+        /*
+         * Take the modification time before we read the directory, so that
+         * any change made while we are reading will trigger another load.
+         */
+        let mtime = std::fs::metadata(&kdir)?.modified()?;
+
+        let mut entries: HashMap<[u8; 32], KeyIndexEntry> = HashMap::new();
+        let mut salted = Vec::new();
+        let mut duplicates = HashMap::new();
+
+        let mut dir = std::fs::read_dir(&kdir)?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_file() {
+                continue;
+            }
+
+            let f = match KeyFile::load(&ent.path()) {
+                Ok(Some(f)) => f,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(
+                        self.log,
+                        "could not load key file {}: {:?}",
+                        ent.path().display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            for k in f.keys.iter() {
+                if k.time_revoke.is_some() {
+                    continue;
+                }
+
+                let ie = KeyIndexEntry {
+                    host: f.host.to_string(),
+                    key_id: k.id.to_string(),
+                    time_expire: k.time_expire,
+                    global_view: f.global_view,
+                    admin: f.admin,
+                    hash: k.hash.clone(),
+                    upgrade: k.hash.is_none(),
+                };
+
+                let hash = if let Some(hash) = k.index() {
+                    hash
+                } else if let Some(kh) = &k.hash {
+                    salted.push((kh.clone(), ie));
+                    continue;
+                } else {
+                    continue;
+                };
+
+                if let Some(other) = entries.get(&hash) {
+                    warn!(
+                        self.log,
+                        "duplicate keys for {} and {}", other.host, f.host
+                    );
+                    duplicates.insert(
+                        hash,
+                        (other.host.to_string(), f.host.to_string()),
+                    );
+                    continue;
+                }
+
+                entries.insert(hash, ie);
+            }
+        }
+
+        Ok(KeyIndex {
+            mtime: Some(mtime),
+            entries,
+            salted,
+            duplicates,
+        })
+    }
+
+    /**
+     * Rebuild the in-memory key index from the files in the "keys"
+     * directory.  Returns the number of keys loaded.
+     */
+    pub fn reload(&self) -> Result<usize> {
+        let index = self.load_index()?;
+        let count = index.entries.len() + index.salted.len();
+        *self.index.lock().unwrap() = index;
+
+        info!(self.log, "loaded {} keys", count);
+        Ok(count)
+    }
+
+    fn invalidate(&self) {
+        self.index.lock().unwrap().mtime = None;
+    }
+
+    fn keypath(&self, set: &str, host: Option<&str>) -> Result<PathBuf> {
+        if let Some(host) = host {
+            if !name_ok(host) {
+                bail!("invalid hostname");
+            }
+        }
+
+        let mut kpath = self.dir.clone();
+        kpath.push(set);
+        std::fs::create_dir_all(&kpath)?;
+        if let Some(host) = host {
+            kpath.push(&format!("{}.json", host));
+        }
+
+        Ok(kpath)
+    }
+
+    fn keys_mtime(&self) -> Result<SystemTime> {
+        let kdir = self.keypath("keys", None)?;
+        Ok(std::fs::metadata(&kdir)?.modified()?)
+    }
+
+    /**
+     * Lock the key index, first loading it again if the keys directory has
+     * changed.
+     */
+    fn current_index(&self) -> Result<std::sync::MutexGuard<'_, KeyIndex>> {
+        let mtime = self.keys_mtime()?;
+
+        let mut index = self.index.lock().unwrap();
+        if index.mtime != Some(mtime) {
+            debug!(self.log, "keys directory changed; reloading index");
+            *index = self.load_index()?;
+        }
+
+        Ok(index)
+    }
+
+    pub fn check_key(&self, key: &str) -> Result<Option<Auth>> {
+        let mut index = self.current_index()?;
+
+        let hash = key_hash(key);
+        if let Some((a, b)) = index.duplicates.get(&hash) {
+            bail!("duplicate keys? {} and {}", a, b);
+        }
+
+        let e = if let Some(e) = index.entries.get(&hash) {
+            /*
+             * The index hash is not salted, so we still check the salted
+             * hash to be sure.
+             */
+            if let Some(kh) = &e.hash {
+                if !kh.matches(key) {
+                    return Ok(None);
+                }
+            }
+            e.clone()
+        } else {
+            /*
+             * Check every salted hash, rather than stopping at the first
+             * match, so that we can still detect duplicate keys.
+             */
+            let mut found: Option<&KeyIndexEntry> = None;
+            for (kh, e) in index.salted.iter() {
+                if kh.matches(key) {
+                    if let Some(found) = found {
+                        bail!("duplicate keys? {} and {}", e.host, found.host);
+                    }
+                    found = Some(e);
+                }
+            }
+
+            if let Some(e) = found.cloned() {
+                let e = KeyIndexEntry { upgrade: true, ..e };
+                index.entries.insert(hash, e.clone());
+                e
+            } else {
+                return Ok(None);
+            }
+        };
+
+        if !e.time_expire.map(|t| Utc::now() < t).unwrap_or(true) {
+            return Ok(None);
+        }
+
+        if e.upgrade {
+            /*
+             * This key is still stored in plain text, or without an index
+             * hash.  Now that we have seen it used, replace it with a hash.
+             * Rewriting the file changes the modification time of the
+             * directory, but the index is otherwise still current, so we
+             * update it in place rather than loading it all again.
+             */
+            let before = self.keys_mtime()?;
+            match self.upgrade_key(&e.host, &e.key_id, key) {
+                Ok(kh) => {
+                    if index.mtime == Some(before) {
+                        index.mtime = Some(self.keys_mtime()?);
+                    }
+                    index.salted.retain(|(_, s)| {
+                        s.host != e.host || s.key_id != e.key_id
+                    });
+                    index.entries.insert(
+                        hash,
+                        KeyIndexEntry {
+                            hash: Some(kh),
+                            upgrade: false,
+                            ..e.clone()
+                        },
+                    );
+                }
+                Err(err) => {
+                    warn!(
+                        self.log,
+                        "could not hash key {} for {}: {:?}",
+                        e.key_id,
+                        e.host,
+                        err
+                    );
+                }
+            }
+        }
+
+        /*
+         * Administrative keys can see everything that a global view key can
+         * see.
+         */
+        Ok(Some(Auth {
+            host: e.host.to_string(),
+            key_id: e.key_id.to_string(),
+            global_view: e.global_view || e.admin,
+            admin: e.admin,
+        }))
+    }
+
+    /**
+     * Replace a key stored in plain text, or a hash without an index hash,
+     * with a complete hash.  Returns the new hash.
+     */
+    fn upgrade_key(
+        &self,
+        host: &str,
+        key_id: &str,
+        key: &str,
+    ) -> Result<KeyHash> {
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let mut f = if let Some(f) = KeyFile::load(&kpath)? {
+            f
+        } else {
+            bail!("key file for {} has disappeared", host);
+        };
+
+        let k = if let Some(k) = f.keys.iter_mut().find(|k| k.id == key_id) {
+            k
+        } else {
+            bail!("key {} for {} has disappeared", key_id, host);
+        };
+        if !k.matches(key) {
+            bail!("key {} for {} has changed", key_id, host);
+        }
+
+        let kh = KeyHash::new(key);
+        k.key = None;
+        k.hash = Some(kh.clone());
+        store_file(&kpath, &f, true)?;
+        info!(self.log, "hashed key {} for host {}", key_id, host);
+
+        Ok(kh)
+    }
+
+    /**
+     * Determine the privileges of a host that has authenticated by some means
+     * other than a key, such as a client certificate.  Such a host need not
+     * have enrolled, but if it has since been revoked, and not approved
+     * again, it is refused; returns None in that case.  Administrative
+     * privileges are only ever granted to a host that presents a key.
+     */
+    pub fn host_auth(&self, host: &str, method: &str) -> Result<Option<Auth>> {
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let global_view = if let Some(f) = KeyFile::load(&kpath)? {
+            f.global_view
+        } else if self.revoked(host)? {
+            return Ok(None);
+        } else {
+            false
+        };
+
+        Ok(Some(Auth {
+            host: host.to_string(),
+            key_id: method.to_string(),
+            global_view,
+            admin: false,
+        }))
+    }
+
+    /**
+     * Has this host ever been revoked?  Revoked key files are named for the
+     * host and the time of revocation.
+     */
+    fn revoked(&self, host: &str) -> Result<bool> {
+        let rdir = self.keypath("revoked", None)?;
+
+        let mut dir = std::fs::read_dir(&rdir)?;
+        while let Some(ent) = dir.next().transpose()? {
+            if let Some(n) = ent.file_name().to_str() {
+                let h = n
+                    .strip_suffix(".json")
+                    .and_then(|n| n.rsplit_once('.'))
+                    .map(|(h, _)| h);
+                if h == Some(host) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub fn enrol_key(&self, host: &str, key: &str) -> Result<bool> {
+        if !name_ok(host) || !key_ok(key) {
+            return Ok(false);
+        }
+
+        /*
+         * If we have already confirmed this host, let's log a warning but
+         * pretend to the client that everything was OK.
+         *
+         * This routine is run by the API layer with the rwlock held for write,
+         * so multiple requests should not race here.
+         */
+        match std::fs::metadata(self.keypath("keys", Some(host))?) {
+            Ok(f) if f.is_file() => {
+                warn!(
+                    self.log,
+                    "re-enrolment for already confirmed host {}", host
+                );
+                return Ok(true);
+            }
+            _ => {}
+        }
+
+        /*
+         * Once the key is hashed with a fresh salt, it can no longer be
+         * compared with salted hashes stored before we kept an index hash, so
+         * we check that the key is not already in use while we have it.  We
+         * do not tell the client why the request was refused.
+         */
+        {
+            let index = self.current_index()?;
+            if let Some(other) = index.key_user(&key_hash(key), Some(key)) {
+                warn!(
+                    self.log,
+                    "enrolment for host {} with key already used by {}",
+                    host,
+                    other
+                );
+                return Ok(false);
+            }
+        }
+
+        let kpath = self.keypath("enrol", Some(host))?;
+
+        let mut buf = serde_json::to_vec_pretty(&KeyFile {
+            host: host.to_string(),
+            key: None,
+            keys: vec![HostKey::new(key)],
+            time_create: Utc::now(),
+            global_view: false,
+            admin: false,
+        })?;
+        buf.push(b'\n');
+
+        let fres = std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(kpath);
+        let f = match fres {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                /*
+                 * Assume the host was over-writing a previous registration
+                 * attempt, without giving away the possibly sensitive fact that
+                 * such a registration occurred to others.
+                 */
+                return Ok(true);
+            }
+            Err(e) => bail!("enrol key failure: {:?}", e),
+        };
+        let mut bw = BufWriter::new(f);
+        bw.write_all(&buf)?;
+        bw.flush()?;
+        Ok(true)
+    }
+
+    pub fn list_hosts(&self) -> Result<Vec<HostInfo>> {
+        let kdir = self.keypath("keys", None)?;
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&kdir)?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_file() {
+                continue;
+            }
+
+            if let Ok(Some(f)) = KeyFile::load(&ent.path()) {
+                out.push(HostInfo {
+                    host: f.host,
+                    time_create: f.time_create,
+                    global_view: f.global_view,
+                    admin: f.admin,
+                });
+            }
+        }
+
+        out.sort_by(|a, b| a.host.cmp(&b.host));
+        Ok(out)
+    }
+
+    /**
+     * Grant or remove the global view privilege for a confirmed host.
+     * Returns false if there is no confirmed key for this host.
+     */
+    pub fn set_global_view(
+        &self,
+        host: &str,
+        global_view: bool,
+    ) -> Result<bool> {
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let mut f = if let Some(f) = KeyFile::load(&kpath)? {
+            f
+        } else {
+            return Ok(false);
+        };
+
+        if f.global_view != global_view {
+            f.global_view = global_view;
+            store_file(&kpath, &f, true)?;
+            self.invalidate();
+            info!(self.log, "host {} global view now {}", host, global_view);
+        }
+
+        Ok(true)
+    }
+
+    /**
+     * Revoke the confirmed key for a host.  The key file is moved aside into
+     * "revoked/", rather than removed, so that there remains a record of
+     * the key that was in use.  Returns false if there is no confirmed key
+     * for this host.
+     */
+    pub fn revoke_host(&self, host: &str) -> Result<bool> {
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let mut rpath = self.keypath("revoked", None)?;
+        rpath.push(format!("{}.{}.json", host, Utc::now().timestamp_millis()));
+
+        match std::fs::rename(&kpath, &rpath) {
+            Ok(()) => {
+                self.invalidate();
+                info!(self.log, "revoked key for host {}", host);
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => bail!("revoke key failure: {:?}", e),
+        }
+    }
+
+    pub fn list_keys(&self, host: &str) -> Result<Option<Vec<KeyInfo>>> {
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let f = if let Some(f) = KeyFile::load(&kpath)? {
+            f
+        } else {
+            return Ok(None);
+        };
+
+        let now = Utc::now();
+        Ok(Some(
+            f.keys
+                .iter()
+                .map(|k| KeyInfo {
+                    id: k.id.to_string(),
+                    time_create: k.time_create,
+                    time_expire: k.time_expire,
+                    time_revoke: k.time_revoke,
+                    valid: k.valid(&now),
+                })
+                .collect(),
+        ))
+    }
+
+    /**
+     * Add a new key for a host, arranging for the key that was used to
+     * authenticate the request to expire once the grace period has elapsed.
+     * The grace period allows jobs that are already running with the old key
+     * to finish their reports.  Returns the ID of the new key.
+     */
+    pub fn rotate_key(
+        &self,
+        host: &str,
+        key_id: &str,
+        new_key: &str,
+        grace: chrono::Duration,
+    ) -> Result<String> {
+        if !key_ok(new_key) {
+            bail!("invalid key format");
+        }
+
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let mut f = if let Some(f) = KeyFile::load(&kpath)? {
+            f
+        } else {
+            bail!("no confirmed key for host {}", host);
+        };
+
+        /*
+         * Make sure the new key is not already in use, either by another host
+         * or by an old key for this host.
+         */
+        if self.check_key(new_key)?.is_some()
+            || f.keys.iter().any(|k| k.matches(new_key))
+        {
+            bail!("new key for {} is already in use", host);
+        }
+
+        let expire = Utc::now() + grace;
+        if let Some(old) = f.keys.iter_mut().find(|k| k.id == key_id) {
+            if old.time_expire.map(|t| expire < t).unwrap_or(true) {
+                old.time_expire = Some(expire);
+            }
+        } else {
+            bail!("key {} for host {} not found", key_id, host);
+        }
+
+        let k = HostKey::new(new_key);
+        let id = k.id.to_string();
+        f.keys.push(k);
+        store_file(&kpath, &f, true)?;
+        self.invalidate();
+
+        info!(self.log, "host {} rotated key {} to {}", host, key_id, id);
+        Ok(id)
+    }
+
+    /**
+     * Revoke a single key for a host, effective immediately.  The key is
+     * retained in the key file, with the time of revocation, for future
+     * reference.  Returns false if there is no such key.
+     */
+    pub fn revoke_key(&self, host: &str, key_id: &str) -> Result<bool> {
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let mut f = if let Some(f) = KeyFile::load(&kpath)? {
+            f
+        } else {
+            return Ok(false);
+        };
+
+        if let Some(k) = f.keys.iter_mut().find(|k| k.id == key_id) {
+            if k.time_revoke.is_none() {
+                k.time_revoke = Some(Utc::now());
+                store_file(&kpath, &f, true)?;
+                self.invalidate();
+                info!(self.log, "revoked key {} for host {}", key_id, host);
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub fn list_enrolments(&self) -> Result<Vec<Enrolment>> {
+        let edir = self.keypath("enrol", None)?;
+
+        let mut out = Vec::new();
+
+        let mut dir = std::fs::read_dir(&edir)?;
+        while let Some(ent) = dir.next().transpose()? {
+            if !ent.file_type()?.is_file() {
+                continue;
+            }
+
+            if let Ok(Some(f)) = KeyFile::load(&ent.path()) {
+                out.push(Enrolment {
+                    host: f.host,
+                    time_create: f.time_create,
+                });
+            }
+        }
+
+        out.sort_by_key(|e| e.time_create);
+        Ok(out)
+    }
+
+    /**
+     * Approve a pending enrolment by moving the enrolment request into the
+     * set of confirmed keys.  Returns false if there is no pending enrolment
+     * for this host.
+     */
+    pub fn approve_enrolment(&self, host: &str) -> Result<bool> {
+        let epath = self.keypath("enrol", Some(host))?;
+        let kpath = self.keypath("keys", Some(host))?;
+
+        let f = if let Some(f) = KeyFile::load(&epath)? {
+            f
+        } else {
+            return Ok(false);
+        };
+
+        if f.host != host {
+            bail!("enrolment file for {} is for host {}", host, f.host);
+        }
+
+        /*
+         * Make sure we would not be confirming a key that some other host is
+         * already using, as that would lock both hosts out.
+         */
+        {
+            let index = self.current_index()?;
+            for k in f.keys.iter() {
+                let hash = if let Some(hash) = k.index() {
+                    hash
+                } else {
+                    continue;
+                };
+
+                if let Some(other) = index.key_user(&hash, k.key.as_deref()) {
+                    return Err(ApproveError::KeyInUse {
+                        host: host.to_string(),
+                        other: other.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        /*
+         * Link the request into place, rather than renaming it, so that we
+         * cannot clobber an existing confirmed key for this host.
+         */
+        match std::fs::hard_link(&epath, &kpath) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(ApproveError::AlreadyConfirmed {
+                    host: host.to_string(),
+                }
+                .into());
+            }
+            Err(e) => bail!("approve enrolment failure: {:?}", e),
+        }
+        std::fs::remove_file(&epath)?;
+        self.invalidate();
+
+        info!(self.log, "approved enrolment for host {}", host);
+        Ok(true)
+    }
+
+    /**
+     * Discard a pending enrolment.  Returns false if there is no pending
+     * enrolment for this host.
+     */
+    pub fn reject_enrolment(&self, host: &str) -> Result<bool> {
+        let epath = self.keypath("enrol", Some(host))?;
+
+        match std::fs::remove_file(&epath) {
+            Ok(()) => {
+                info!(self.log, "rejected enrolment for host {}", host);
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => bail!("reject enrolment failure: {:?}", e),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct OutputRecord {
+    pub time: DateTime<Utc>,
+    pub stream: String,
+    pub msg: String,
+    /**
+     * Assigned by the client as each line is read, starting from zero for
+     * each report.  Records from older clients do not have one.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PostFile {
+    pub report_pid: u32,
+    pub report_uuid: String,
+    pub report_time: DateTime<Utc>,
+    pub time_start: DateTime<Utc>,
+    pub time_end: Option<DateTime<Utc>>,
+    pub script: String,
+    pub duration: Option<u64>,
+    pub status: Option<i32>,
+    /**
+     * Output records are stored in separate logs, and are only included here
+     * when the report is loaded with ReportStore::load().  Reports from
+     * before that change have their output stored in the report file.
+     */
+    #[serde(default)]
+    pub output: Vec<OutputRecord>,
+    /**
+     * Once the output of a job is large enough that some of it must be
+     * discarded, the most recent records are kept here.
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub output_tail: Vec<OutputRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<OutputTruncation>,
+    /**
+     * Set once the report is sealed.  Reports from older clients do not
+     * include an outcome, but were necessarily completed.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<ReportOutcome>,
+    /**
+     * When the client last told us that the job was still running.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rusage: Option<ResourceUsage>,
+    /**
+     * Reports from older clients include only an exit status, which was
+     * std::i32::MAX if the process did not exit normally.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub termination: Option<Termination>,
+    #[serde(default)]
+    pub sealed: bool,
+}
+
+/**
+ * Limits on the output kept for each report.  Half of each limit is used for
+ * the first records we receive, and half for the most recent records.
+ */
+#[derive(Clone, Copy)]
+pub struct OutputLimits {
+    pub max_lines: usize,
+    pub max_bytes: usize,
+}
+
+/**
+ * Describes output records that were discarded because a report exceeded the
+ * output limits.
+ */
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct OutputTruncation {
+    /**
+     * The number of records that were kept before the discarded records.
+     */
+    pub after: u64,
+    pub omitted_lines: u64,
+    pub omitted_bytes: u64,
+}
+
+impl PostFile {
+    /**
+     * A report that was abandoned because we stopped hearing from the client
+     * may yet be completed, if the client was only out of touch for a while.
+     * Returns true if the report was abandoned, and is now open again.
+     */
+    pub fn reopen(&mut self) -> bool {
+        if !self.sealed || self.outcome != Some(ReportOutcome::Abandoned) {
+            return false;
+        }
+
+        self.sealed = false;
+        self.outcome = None;
+        self.time_end = None;
+        self.duration = None;
+        self.last_heartbeat = Some(Utc::now());
+        true
+    }
+
+    /**
+     * All of the output records we have kept, in order.
+     */
+    pub fn output_records(&self) -> impl Iterator<Item = &OutputRecord> {
+        self.output.iter().chain(self.output_tail.iter())
+    }
+
+    pub fn duration_seconds(&self) -> Option<i32> {
+        self.duration.map(|d| u64ton(d / 1000))
+    }
+}
+
+pub struct Auth {
+    pub host: String,
+    pub key_id: String,
+    pub global_view: bool,
+    pub admin: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * A data directory for the stores, which is removed when the test is
+     * done with it.
+     */
+    struct TestStore {
+        dir: PathBuf,
+    }
+
+    impl TestStore {
+        fn new(name: &str) -> TestStore {
+            let dir = std::env::temp_dir().join(format!(
+                "keeper-store-test-{}-{}",
+                std::process::id(),
+                name,
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TestStore { dir }
+        }
+
+        /**
+         * Open the store, as the server would when it starts.
+         */
+        fn open(&self) -> ReportStore {
+            let log = Logger::root(slog::Discard, slog::o!());
+            ReportStore::new(log, &self.dir).unwrap()
+        }
+
+        fn keys(&self) -> KeyStore {
+            let log = Logger::root(slog::Discard, slog::o!());
+            KeyStore::new(log, &self.dir).unwrap()
+        }
+
+        /**
+         * Write a key file by hand, as an older server might have.
+         */
+        fn write_keys(&self, set: &str, host: &str, hk: HostKey) {
+            let dir = self.dir.join(set);
+            std::fs::create_dir_all(&dir).unwrap();
+            let kf = KeyFile {
+                host: host.to_string(),
+                key: None,
+                keys: vec![hk],
+                time_create: when(),
+                global_view: false,
+                admin: false,
+            };
+            store_file(&dir.join(format!("{}.json", host)), &kf, true).unwrap();
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn when() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 3, 0, 0).unwrap()
+    }
+
+    fn rec(seq: u64) -> OutputRecord {
+        OutputRecord {
+            time: when(),
+            stream: "stdout".to_string(),
+            msg: format!("line {}", seq),
+            seq: Some(seq),
+        }
+    }
+
+    /**
+     * Make sure the report directory exists, and return the paths of the
+     * head and tail output logs.
+     */
+    fn logs(rs: &ReportStore) -> (PathBuf, PathBuf) {
+        let (head, tail) = rs.outputpaths("web01", "backup", &when()).unwrap();
+        std::fs::create_dir_all(head.parent().unwrap()).unwrap();
+        (head, tail)
+    }
+
+    fn append(rs: &ReportStore, seq: u64, limits: &OutputLimits) -> bool {
+        rs.append_output("web01", "backup", &when(), &rec(seq), limits)
+            .unwrap()
+    }
+
+    fn seqs(p: &Path) -> Vec<Option<u64>> {
+        read_log(p)
+            .unwrap()
+            .into_iter()
+            .filter_map(|e| match e {
+                OutputLogEntry::Record(r) => Some(r.seq),
+                OutputLogEntry::Truncation(_) => None,
+            })
+            .collect()
+    }
+
+    const LIMITS: OutputLimits = OutputLimits {
+        max_lines: 4,
+        max_bytes: 1 << 20,
+    };
+
+    #[test]
+    fn seen_seqs_duplicates() {
+        let mut s = SeenSeqs::default();
+
+        assert!(s.insert(0));
+        assert!(s.insert(1));
+        assert!(!s.insert(0));
+        assert!(!s.insert(1));
+        assert_eq!(s.below, 2);
+        assert!(s.above.is_empty());
+    }
+
+    #[test]
+    fn seen_seqs_out_of_order() {
+        let mut s = SeenSeqs::default();
+
+        assert!(s.insert(2));
+        assert!(s.insert(4));
+        assert!(!s.insert(4));
+        assert_eq!(s.below, 0);
+
+        assert!(s.insert(0));
+        assert_eq!(s.below, 1);
+        assert!(s.insert(1));
+        assert_eq!(s.below, 3);
+        assert!(s.insert(3));
+        assert_eq!(s.below, 5);
+        assert!(s.above.is_empty());
+
+        assert!(!s.insert(2));
+    }
+
+    #[test]
+    fn seen_seqs_skip() {
+        let mut s = SeenSeqs::default();
+
+        assert!(s.insert(0));
+        assert!(s.insert(12));
+        s.skip_to(10);
+        assert_eq!(s.below, 10);
+        assert!(!s.insert(5));
+        assert!(!s.insert(12));
+
+        assert!(s.insert(10));
+        assert!(s.insert(11));
+        assert_eq!(s.below, 13);
+
+        /*
+         * Skipping backwards changes nothing.
+         */
+        s.skip_to(3);
+        assert_eq!(s.below, 13);
+    }
+
+    #[test]
+    fn compact_tail_limits() {
+        let ts = TestStore::new("compact");
+        let rs = ts.open();
+        let (_, tail) = logs(&rs);
+
+        for seq in 0..6 {
+            append_log(&tail, &rec(seq)).unwrap();
+        }
+
+        /*
+         * A tail within the limits is left alone.
+         */
+        let (lines, _, trunc) = rs.compact_tail(&tail, 3, 6, 1000).unwrap();
+        assert_eq!(lines, 6);
+        assert!(trunc.is_none());
+
+        let (lines, bytes, trunc) = rs.compact_tail(&tail, 3, 4, 1000).unwrap();
+        assert_eq!(lines, 4);
+        assert_eq!(bytes, 4 * "line 0".len());
+        let trunc = trunc.unwrap();
+        assert_eq!(trunc.after, 3);
+        assert_eq!(trunc.omitted_lines, 2);
+        assert_eq!(trunc.omitted_bytes, 2 * "line 0".len() as u64);
+        assert_eq!(seqs(&tail), vec![Some(2), Some(3), Some(4), Some(5)]);
+
+        /*
+         * Compacting again adds to the description of what was discarded,
+         * which remains at the start of the log.
+         */
+        append_log(&tail, &rec(6)).unwrap();
+        let (lines, _, trunc) = rs.compact_tail(&tail, 3, 2, 1000).unwrap();
+        assert_eq!(lines, 2);
+        let trunc = trunc.unwrap();
+        assert_eq!(trunc.after, 3);
+        assert_eq!(trunc.omitted_lines, 5);
+        assert!(matches!(
+            read_log(&tail).unwrap().first(),
+            Some(OutputLogEntry::Truncation(_))
+        ));
+        assert_eq!(seqs(&tail), vec![Some(5), Some(6)]);
+
+        /*
+         * The byte limit applies as well as the line limit.
+         */
+        let (lines, bytes, trunc) =
+            rs.compact_tail(&tail, 3, 10, "line 0".len()).unwrap();
+        assert_eq!((lines, bytes), (1, "line 0".len()));
+        assert_eq!(trunc.unwrap().omitted_lines, 6);
+        assert_eq!(seqs(&tail), vec![Some(6)]);
+    }
+
+    #[test]
+    fn output_across_limit() {
+        let ts = TestStore::new("limit");
+        let rs = ts.open();
+        let (head, tail) = logs(&rs);
+
+        /*
+         * The first half of the line limit goes to the head log, and the
+         * tail log is compacted once it reaches twice the other half.
+         */
+        for seq in 0..7 {
+            assert!(append(&rs, seq, &LIMITS));
+        }
+        assert_eq!(seqs(&head), vec![Some(0), Some(1)]);
+        assert_eq!(seqs(&tail), vec![Some(5), Some(6)]);
+
+        /*
+         * Records that were discarded, or that are still held, must not be
+         * stored again if the client sends them a second time.
+         */
+        assert!(!append(&rs, 3, &LIMITS));
+        assert!(!append(&rs, 6, &LIMITS));
+        assert!(!append(&rs, 0, &LIMITS));
+
+        assert!(append(&rs, 7, &LIMITS));
+        let trunc = rs
+            .finish_output("web01", "backup", &when(), &LIMITS)
+            .unwrap()
+            .unwrap();
+        assert_eq!(trunc.after, 2);
+        assert_eq!(trunc.omitted_lines, 4);
+        assert_eq!(seqs(&tail), vec![Some(6), Some(7)]);
+        assert!(rs.open.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reopen_after_compaction() {
+        let ts = TestStore::new("recompact");
+        let (head, tail) = logs(&ts.open());
+
+        {
+            let rs = ts.open();
+            for seq in 0..7 {
+                assert!(append(&rs, seq, &LIMITS));
+            }
+        }
+
+        /*
+         * A new store, as after a restart, knows nothing of what it saw
+         * before and must work it out from the logs.  Anything older than
+         * the first record left in the compacted tail was discarded, and so
+         * must not be stored again.
+         */
+        let rs = ts.open();
+        assert!(!append(&rs, 1, &LIMITS));
+        assert!(!append(&rs, 3, &LIMITS));
+        assert!(!append(&rs, 5, &LIMITS));
+        assert!(append(&rs, 7, &LIMITS));
+        assert_eq!(seqs(&head), vec![Some(0), Some(1)]);
+        assert_eq!(seqs(&tail), vec![Some(5), Some(6), Some(7)]);
+    }
+
+    #[test]
+    fn reopen_after_partial_write() {
+        let ts = TestStore::new("partial");
+        let (head, _) = logs(&ts.open());
+        let limits = OutputLimits {
+            max_lines: 100,
+            max_bytes: 1 << 20,
+        };
+
+        {
+            let rs = ts.open();
+            for seq in 0..2 {
+                assert!(append(&rs, seq, &limits));
+            }
+        }
+
+        /*
+         * Simulate a crash part of the way through appending a record.
+         */
+        let mut f = OpenOptions::new().append(true).open(&head).unwrap();
+        f.write_all(br#"{"time":"2024-05-01T03:00:00Z","str"#)
+            .unwrap();
+        drop(f);
+
+        let rs = ts.open();
+        assert!(!append(&rs, 1, &limits));
+        assert!(append(&rs, 2, &limits));
+        assert_eq!(seqs(&head), vec![Some(0), Some(1), Some(2)]);
+
+        /*
+         * The incomplete line must have been removed, rather than left in
+         * front of the record we appended.
+         */
+        let data = std::fs::read_to_string(&head).unwrap();
+        assert_eq!(data.lines().count(), 3);
+        assert!(data.ends_with('\n'));
+    }
+
+    fn running(time: DateTime<Utc>) -> PostFile {
+        PostFile {
+            report_pid: 1,
+            report_uuid: "uuid".to_string(),
+            report_time: time,
+            time_start: time,
+            time_end: None,
+            script: "backup".to_string(),
+            duration: None,
+            status: None,
+            output: Vec::new(),
+            output_tail: Vec::new(),
+            truncation: None,
+            outcome: None,
+            last_heartbeat: Some(time + chrono::Duration::seconds(90)),
+            rusage: None,
+            termination: None,
+            sealed: false,
+        }
+    }
+
+    #[test]
+    fn abandon_finishes_output() {
+        let ts = TestStore::new("abandon");
+        let rs = ts.open();
+
+        rs.store("web01", "backup", &when(), &running(when()))
+            .unwrap();
+        for seq in 0..6 {
+            assert!(append(&rs, seq, &LIMITS));
+        }
+
+        let abandoned = rs.abandon(&Utc::now(), &LIMITS).unwrap();
+        assert_eq!(abandoned.len(), 1);
+        assert!(rs.open.lock().unwrap().is_empty());
+
+        let f = rs.load("web01", "backup", &when()).unwrap().unwrap();
+        assert!(f.sealed);
+        assert_eq!(f.outcome, Some(ReportOutcome::Abandoned));
+        assert_eq!(f.duration, Some(90_000));
+        assert_eq!(f.truncation.as_ref().unwrap().omitted_lines, 2);
+        let seqs = f.output_records().map(|r| r.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![Some(0), Some(1), Some(4), Some(5)]);
+    }
+
+    #[test]
+    fn prune_keeps_running() {
+        let ts = TestStore::new("prune");
+        let rs = ts.open();
+
+        let earlier = when() - chrono::Duration::days(1);
+        rs.store("web01", "backup", &when(), &running(when()))
+            .unwrap();
+        let done = PostFile {
+            sealed: true,
+            status: Some(0),
+            ..running(earlier)
+        };
+        rs.store("web01", "backup", &earlier, &done).unwrap();
+
+        assert_eq!(rs.prune(&Utc::now(), None, None).unwrap(), 1);
+        assert!(rs.load_meta("web01", "backup", &earlier).unwrap().is_none());
+        assert!(rs.load_meta("web01", "backup", &when()).unwrap().is_some());
+        assert_eq!(rs.running().unwrap().len(), 1);
+    }
+
+    fn plain_key(key: &str) -> HostKey {
+        HostKey {
+            key: Some(key.to_string()),
+            hash: None,
+            ..HostKey::new(key)
+        }
+    }
+
+    fn salted_key(key: &str) -> HostKey {
+        let hk = HostKey::new(key);
+        HostKey {
+            hash: hk.hash.map(|kh| KeyHash { index: None, ..kh }),
+            ..hk
+        }
+    }
+
+    fn key_in_use(res: Result<bool>) -> Option<String> {
+        match res.unwrap_err().downcast::<ApproveError>() {
+            Ok(ApproveError::KeyInUse { other, .. }) => Some(other),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn enrol_key_in_use() {
+        let ts = TestStore::new("enrol");
+        let key = genkey(64);
+        ts.write_keys("keys", "web01", salted_key(&key));
+        let ks = ts.keys();
+
+        /*
+         * A salted hash without an index can only be compared with the key
+         * while we have it, so the enrolment itself must be refused.
+         */
+        assert!(!ks.enrol_key("web02", &key).unwrap());
+        assert!(!ts.dir.join("enrol").join("web02.json").exists());
+
+        assert!(ks.enrol_key("web02", &genkey(64)).unwrap());
+        assert!(ks.approve_enrolment("web02").unwrap());
+    }
+
+    #[test]
+    fn approve_key_in_use() {
+        let ts = TestStore::new("approve");
+        let key = genkey(64);
+        ts.write_keys("keys", "web01", plain_key(&key));
+        ts.write_keys("enrol", "web02", HostKey::new(&key));
+        let ks = ts.keys();
+
+        let res = ks.approve_enrolment("web02");
+        assert_eq!(key_in_use(res).as_deref(), Some("web01"));
+        assert!(ts.dir.join("enrol").join("web02.json").exists());
+        assert!(!ts.dir.join("keys").join("web02.json").exists());
+    }
+
+    #[test]
+    fn approve_key_in_use_duplicate() {
+        let ts = TestStore::new("duplicate");
+        let key = genkey(64);
+        ts.write_keys("keys", "web01", HostKey::new(&key));
+        ts.write_keys("keys", "web03", HostKey::new(&key));
+        ts.write_keys("enrol", "web02", HostKey::new(&key));
+        let ks = ts.keys();
+
+        let other = key_in_use(ks.approve_enrolment("web02")).unwrap();
+        assert!(other == "web01" || other == "web03");
+    }
+
+    #[test]
+    fn approve_key_in_use_salted() {
+        let ts = TestStore::new("salted");
+        let key = genkey(64);
+        ts.write_keys("keys", "web01", salted_key(&key));
+        ts.write_keys("enrol", "web02", plain_key(&key));
+        let ks = ts.keys();
+
+        let res = ks.approve_enrolment("web02");
+        assert_eq!(key_in_use(res).as_deref(), Some("web01"));
+    }
+
+    #[test]
+    fn repair_complete_log() {
+        let ts = TestStore::new("repair");
+        let (head, tail) = logs(&ts.open());
+
+        /*
+         * A missing log, or one that ends with a complete line, is left
+         * alone.
+         */
+        repair_log(&tail).unwrap();
+        assert!(!tail.exists());
+
+        append_log(&head, &rec(0)).unwrap();
+        let before = std::fs::read(&head).unwrap();
+        repair_log(&head).unwrap();
+        assert_eq!(std::fs::read(&head).unwrap(), before);
+
+        /*
+         * A log with only an incomplete line is emptied.
+         */
+        std::fs::write(&tail, b"{\"time\"").unwrap();
+        repair_log(&tail).unwrap();
+        assert!(std::fs::read(&tail).unwrap().is_empty());
+    }
+}
@@ -1,15 +1,54 @@
-use super::OutputRecord;
+use super::{OutputRecord, ResourceUsage, Termination};
 use anyhow::Result;
 use chrono::prelude::*;
 use std::ffi::OsStr;
 use std::io::{BufRead, BufReader, Read};
-use std::os::unix::process::ExitStatusExt;
-use std::process::{Command, Stdio};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::time::Instant;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-fn spawn_reader<T>(
+/**
+ * Output records are numbered as they are read.  The counter remains locked
+ * while each record is sent, so that records from different streams arrive on
+ * the channel in the order they were numbered.
+ */
+#[derive(Clone)]
+struct OutputSender {
     tx: Sender<Activity>,
+    seq: Arc<Mutex<u64>>,
+}
+
+impl OutputSender {
+    fn new(tx: Sender<Activity>) -> OutputSender {
+        OutputSender {
+            tx,
+            seq: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    fn send(&self, stream: &str, msg: &str) {
+        let mut seq = self.seq.lock().unwrap();
+        self.tx
+            .send(Activity::Output(OutputDetails {
+                stream: stream.to_string(),
+                msg: msg.to_string(),
+                time: Utc::now(),
+                seq: *seq,
+            }))
+            .unwrap();
+        *seq += 1;
+    }
+
+    fn err(&self, msg: &str) {
+        self.send("error", msg);
+    }
+}
+
+fn spawn_reader<T>(
+    out: OutputSender,
     name: &str,
     stream: Option<T>,
 ) -> Option<std::thread::JoinHandle<()>>
@@ -43,18 +82,14 @@ where
                 Ok(_) => {
                     let s = String::from_utf8_lossy(&buf);
 
-                    tx.send(Activity::msg(&name, s.trim_end())).unwrap();
+                    out.send(&name, s.trim_end());
                 }
                 Err(e) => {
                     /*
                      * Try to report whatever error we experienced to the
                      * server:
                      */
-                    tx.send(Activity::msg(
-                        "error",
-                        &format!("failed to read {}: {:?}", name, e),
-                    ))
-                    .unwrap();
+                    out.err(&format!("failed to read {}: {:?}", name, e));
                     return;
                 }
             }
@@ -66,6 +101,111 @@ pub struct ExitDetails {
     pub duration_ms: u64,
     pub when: DateTime<Utc>,
     pub code: i32,
+    pub timed_out: bool,
+    pub rusage: Option<ResourceUsage>,
+    pub termination: Termination,
+}
+
+fn millis(tv: &libc::timeval) -> u64 {
+    (tv.tv_sec.max(0) as u64) * 1000 + (tv.tv_usec.max(0) as u64) / 1000
+}
+
+/**
+ * Reap the job, collecting its resource usage as we go.  The usage includes
+ * any descendants the job itself waited for.
+ */
+fn wait_rusage(
+    pid: libc::pid_t,
+) -> std::io::Result<(ExitStatus, ResourceUsage)> {
+    let mut status: libc::c_int = 0;
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        if unsafe { libc::wait4(pid, &mut status, 0, &mut ru) } == pid {
+            break;
+        }
+
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    /*
+     * The maximum resident set size is in bytes on macOS, but in kilobytes
+     * elsewhere.
+     */
+    let maxrss = ru.ru_maxrss.max(0) as u64;
+    #[cfg(target_os = "macos")]
+    let maxrss = maxrss / 1024;
+
+    Ok((
+        ExitStatus::from_raw(status),
+        ResourceUsage {
+            user_cpu_millis: millis(&ru.ru_utime),
+            system_cpu_millis: millis(&ru.ru_stime),
+            max_rss_kib: maxrss,
+            block_input: ru.ru_inblock.max(0) as u64,
+            block_output: ru.ru_oublock.max(0) as u64,
+            voluntary_context_switches: ru.ru_nvcsw.max(0) as u64,
+            involuntary_context_switches: ru.ru_nivcsw.max(0) as u64,
+        },
+    ))
+}
+
+/**
+ * If a job runs for longer than "after", we send SIGTERM to every process in
+ * its process group, and if it has still not exited once the grace period has
+ * elapsed, SIGKILL.
+ */
+#[derive(Clone, Copy)]
+pub struct Timeout {
+    pub after: Duration,
+    pub grace: Duration,
+}
+
+fn kill_group(pgid: libc::pid_t, sig: libc::c_int) {
+    /*
+     * The group may already be gone, in which case there is nothing to do.
+     */
+    unsafe { libc::kill(-pgid, sig) };
+}
+
+/**
+ * Wait for the job to finish, killing it if it exceeds the timeout.  The
+ * sender is dropped once the job has been reaped.
+ */
+fn spawn_timer(
+    out: OutputSender,
+    pgid: libc::pid_t,
+    t: Timeout,
+    done: Receiver<()>,
+    timed_out: Arc<AtomicBool>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        if !matches!(done.recv_timeout(t.after), Err(RecvTimeoutError::Timeout))
+        {
+            return;
+        }
+
+        timed_out.store(true, Ordering::SeqCst);
+        out.err(&format!(
+            "job timed out after {} seconds; sending SIGTERM",
+            t.after.as_secs()
+        ));
+        kill_group(pgid, libc::SIGTERM);
+
+        if !matches!(done.recv_timeout(t.grace), Err(RecvTimeoutError::Timeout))
+        {
+            return;
+        }
+
+        out.err(&format!(
+            "job still running {} seconds after SIGTERM; sending SIGKILL",
+            t.grace.as_secs()
+        ));
+        kill_group(pgid, libc::SIGKILL);
+    })
 }
 
 #[derive(Clone)]
@@ -73,6 +213,7 @@ pub struct OutputDetails {
     stream: String,
     msg: String,
     time: DateTime<Utc>,
+    seq: u64,
 }
 
 impl OutputDetails {
@@ -81,6 +222,7 @@ impl OutputDetails {
             stream: self.stream.to_string(),
             msg: self.msg.to_string(),
             time: self.time,
+            seq: Some(self.seq),
         }
     }
 }
@@ -92,32 +234,29 @@ pub enum Activity {
 }
 
 impl Activity {
-    fn exit(start: &Instant, end: &Instant, code: i32) -> Activity {
+    fn exit(
+        start: &Instant,
+        end: &Instant,
+        code: i32,
+        timed_out: bool,
+        rusage: Option<ResourceUsage>,
+        termination: Termination,
+    ) -> Activity {
         Activity::Exit(ExitDetails {
             duration_ms: end.duration_since(*start).as_millis() as u64,
             when: Utc::now(),
             code,
-        })
-    }
-
-    fn msg(stream: &str, msg: &str) -> Activity {
-        Activity::Output(OutputDetails {
-            stream: stream.to_string(),
-            msg: msg.to_string(),
-            time: Utc::now(),
-        })
-    }
-
-    fn err(msg: &str) -> Activity {
-        Activity::Output(OutputDetails {
-            stream: "error".to_string(),
-            msg: msg.to_string(),
-            time: Utc::now(),
+            timed_out,
+            rusage,
+            termination,
         })
     }
 }
 
-pub fn run<S: AsRef<OsStr>>(args: &[S]) -> Result<Receiver<Activity>> {
+pub fn run<S: AsRef<OsStr>>(
+    args: &[S],
+    timeout: Option<Timeout>,
+) -> Result<Receiver<Activity>> {
     let args: Vec<&OsStr> = args.iter().map(|s| s.as_ref()).collect();
 
     let (tx, rx) = channel::<Activity>();
@@ -132,11 +271,29 @@ pub fn run<S: AsRef<OsStr>>(args: &[S]) -> Result<Receiver<Activity>> {
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
 
+    /*
+     * If there is a timeout, put the job in a process group of its own, so
+     * that if it times out we can kill anything it has started as well.
+     * Otherwise we leave the job in our process group, so that signals from
+     * the terminal (e.g., ^C) still reach it.
+     */
+    if timeout.is_some() {
+        cmd.process_group(0);
+    }
+
     let start = Instant::now();
     let mut child = cmd.spawn()?;
+    let pid = child.id() as libc::pid_t;
+
+    let out = OutputSender::new(tx.clone());
+    let readout = spawn_reader(out.clone(), "stdout", child.stdout.take());
+    let readerr = spawn_reader(out.clone(), "stderr", child.stderr.take());
 
-    let readout = spawn_reader(tx.clone(), "stdout", child.stdout.take());
-    let readerr = spawn_reader(tx.clone(), "stderr", child.stderr.take());
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let (done_tx, done_rx) = channel::<()>();
+    let timer = timeout.map(|t| {
+        spawn_timer(out.clone(), pid, t, done_rx, Arc::clone(&timed_out))
+    });
 
     std::thread::spawn(move || {
         if let Some(t) = readout {
@@ -146,29 +303,68 @@ pub fn run<S: AsRef<OsStr>>(args: &[S]) -> Result<Receiver<Activity>> {
             t.join().expect("join stderr thread");
         }
 
-        let wait = child.wait();
+        /*
+         * We reap the child ourselves, rather than through the standard
+         * library, so that we can find out what resources it used.
+         */
+        let wait = wait_rusage(pid);
+        drop(child);
         let end = Instant::now();
+
+        /*
+         * Make sure the timer has stopped before we report the exit, so that
+         * any messages it sends arrive first.
+         */
+        drop(done_tx);
+        if let Some(t) = timer {
+            t.join().expect("join timer thread");
+        }
+        let timed_out = timed_out.load(Ordering::SeqCst);
+
         match wait {
             Err(e) => {
-                tx.send(Activity::err(&format!("child wait error: {:?}", e)))
-                    .unwrap();
-                tx.send(Activity::exit(&start, &end, std::i32::MAX))
-                    .unwrap();
+                out.err(&format!("child wait error: {:?}", e));
+                tx.send(Activity::exit(
+                    &start,
+                    &end,
+                    std::i32::MAX,
+                    timed_out,
+                    None,
+                    Termination::WaitError {
+                        message: e.to_string(),
+                    },
+                ))
+                .unwrap();
             }
-            Ok(es) => {
-                if let Some(sig) = es.signal() {
-                    tx.send(Activity::err(&format!(
-                        "child terminated by signal {}",
-                        sig
-                    )))
-                    .unwrap();
-                }
+            Ok((es, ru)) => {
+                /*
+                 * Older servers require an exit status, so we still send
+                 * std::i32::MAX for a process that did not exit normally.
+                 */
                 let code = if let Some(code) = es.code() {
                     code
                 } else {
                     std::i32::MAX
                 };
-                tx.send(Activity::exit(&start, &end, code)).unwrap();
+                let termination = if let Some(sig) = es.signal() {
+                    out.err(&format!("child terminated by signal {}", sig));
+                    if es.core_dumped() {
+                        Termination::CoreDumped { signal: sig }
+                    } else {
+                        Termination::Signalled { signal: sig }
+                    }
+                } else {
+                    Termination::Exited { code }
+                };
+                tx.send(Activity::exit(
+                    &start,
+                    &end,
+                    code,
+                    timed_out,
+                    Some(ru),
+                    termination,
+                ))
+                .unwrap();
             }
         }
 
@@ -0,0 +1,71 @@
+/*
+ * A lock file for each job, so that a slow job is not started again by cron
+ * while a previous run is still going.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /**
+     * Wait for any previous run to finish.
+     */
+    Wait,
+    /**
+     * Give up if a previous run has not finished.
+     */
+    Skip,
+}
+
+/**
+ * The lock is held until this is dropped.  The lock file is opened with
+ * close-on-exec, so the job itself does not inherit it.
+ */
+pub struct JobLock {
+    _f: File,
+}
+
+/**
+ * Take the lock for a job.  In Skip mode, returns None if the lock is held by
+ * somebody else.
+ */
+pub fn lock(dir: &Path, job: &str, mode: LockMode) -> Result<Option<JobLock>> {
+    if job.contains('/') {
+        bail!("invalid job name {:?}", job);
+    }
+
+    std::fs::create_dir_all(dir).with_context(|| {
+        format!("creating lock directory {}", dir.display())
+    })?;
+
+    let p = dir.join(format!("{}.lock", job));
+    let f = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&p)
+        .with_context(|| format!("opening lock file {}", p.display()))?;
+
+    let mut op = libc::LOCK_EX;
+    if mode == LockMode::Skip {
+        op |= libc::LOCK_NB;
+    }
+
+    loop {
+        if unsafe { libc::flock(f.as_raw_fd(), op) } == 0 {
+            return Ok(Some(JobLock { _f: f }));
+        }
+
+        let e = std::io::Error::last_os_error();
+        match e.kind() {
+            ErrorKind::Interrupted => continue,
+            ErrorKind::WouldBlock if mode == LockMode::Skip => return Ok(None),
+            _ => bail!("locking {}: {}", p.display(), e),
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail, Result};
 use chrono::prelude::*;
@@ -8,19 +10,82 @@ use keeper_openapi::{types::*, Client};
 use serde::{Deserialize, Serialize};
 
 mod exec;
+mod lock;
+mod retry;
+mod spool;
 use exec::Activity;
-
-#[derive(Serialize, Deserialize)]
+use lock::LockMode;
+use retry::Policy;
+use spool::{SpoolEntry, SpoolFile};
+
+/**
+ * The configuration file is written by "keeper-submit enrol".  Hosts that
+ * authenticate with a client certificate rather than a key do not need to
+ * enrol; the file can be written by hand with "client_cert" and "client_key"
+ * in place of "key".
+ */
+#[derive(Clone, Serialize, Deserialize)]
 struct ConfigFile {
     baseurl: String,
     host: String,
-    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_key: Option<PathBuf>,
+    /**
+     * A PEM bundle of additional certificate authorities to trust when
+     * connecting to the server.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_file: Option<PathBuf>,
+    /**
+     * Where to keep reports that could not be delivered to the server.  If
+     * not specified, "~/.keeper-spool" is used.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spool_dir: Option<PathBuf>,
+    /**
+     * Where to keep the lock files used to prevent overlapping runs of a
+     * job.  If not specified, "~/.keeper-locks" is used.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock_dir: Option<PathBuf>,
+    /**
+     * How long to keep retrying a request to the server before giving up.
+     * Jobs run with "exec" or "cron" then write their report to the spool,
+     * and if not specified, give up after 60 seconds.  Other commands retry
+     * indefinitely unless this is specified.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_seconds: Option<u64>,
+}
+
+impl ConfigFile {
+    fn retry_deadline(&self) -> Option<Duration> {
+        self.retry_seconds.map(Duration::from_secs)
+    }
 }
 
 fn make_client(cf: &ConfigFile) -> Result<Client> {
-    keeper_openapi::ClientBuilder::new(&cf.baseurl)
-        .bearer_token(&cf.key)
-        .build()
+    let mut b = keeper_openapi::ClientBuilder::new(&cf.baseurl);
+    match (&cf.key, &cf.client_cert, &cf.client_key) {
+        (Some(key), None, None) => {
+            b.bearer_token(key);
+        }
+        (None, Some(cert), Some(key)) => {
+            b.client_cert(cert, key);
+        }
+        _ => bail!(
+            "configuration must have either a key, or a client certificate \
+            and key, but not both"
+        ),
+    }
+    if let Some(ca_file) = &cf.ca_file {
+        b.ca_file(ca_file);
+    }
+    b.build()
 }
 
 #[tokio::main]
@@ -33,12 +98,22 @@ async fn main() -> Result<()> {
         cmd!(cmd_enrol),
     )?;
     l.cmd("ping", "ping the keeper server", cmd!(cmd_ping))?;
+    l.cmd(
+        "rotate",
+        "replace the key used to authenticate with the keeper server",
+        cmd!(cmd_rotate),
+    )?;
     l.cmd("exec", "execute a job under keeper control", cmd!(cmd_exec))?;
     l.cmd(
         "cron",
         "like exec, but for cron; no stdio output is generated",
         cmd!(cmd_cron),
     )?;
+    l.cmd(
+        "flush",
+        "deliver reports spooled while the keeper server was unavailable",
+        cmd!(cmd_flush),
+    )?;
 
     sel!(l).run().await
 }
@@ -62,8 +137,33 @@ fn load_config() -> Result<LoadedConfig> {
     })
 }
 
+fn dir_or_home(dir: &Option<PathBuf>, name: &str) -> Result<PathBuf> {
+    if let Some(dir) = dir {
+        Ok(dir.clone())
+    } else if let Some(mut home) = dirs::home_dir() {
+        home.push(name);
+        Ok(home)
+    } else {
+        bail!("could not find home directory");
+    }
+}
+
+fn spool_dir(cf: &ConfigFile) -> Result<PathBuf> {
+    dir_or_home(&cf.spool_dir, ".keeper-spool")
+}
+
+fn lock_dir(cf: &ConfigFile) -> Result<PathBuf> {
+    dir_or_home(&cf.lock_dir, ".keeper-locks")
+}
+
 async fn cmd_enrol(mut l: Level<()>) -> Result<()> {
     l.usage_args(Some("NODENAME URL"));
+    l.optopt(
+        "C",
+        "",
+        "trust certificate authorities in this PEM file",
+        "CA_FILE",
+    );
 
     let a = args!(l);
 
@@ -74,17 +174,27 @@ async fn cmd_enrol(mut l: Level<()>) -> Result<()> {
     }
     let host = a.args()[0].to_string();
     let baseurl = a.args()[1].to_string();
+    let ca_file = a.opts().opt_str("C").map(PathBuf::from);
 
     let cf = if let Some(cf) = lc.config {
         if host != cf.host || baseurl != cf.baseurl {
             bail!("conflicting enrolment already exists");
         }
+        if cf.key.is_none() {
+            bail!("configured to use a client certificate; no need to enrol");
+        }
         cf
     } else {
         let cf = ConfigFile {
             baseurl,
             host,
-            key: genkey(64),
+            key: Some(genkey(64)),
+            client_cert: None,
+            client_key: None,
+            ca_file,
+            spool_dir: None,
+            lock_dir: None,
+            retry_seconds: None,
         };
         store_file(&lc.path, &cf, true)?;
         cf
@@ -94,35 +204,16 @@ async fn cmd_enrol(mut l: Level<()>) -> Result<()> {
 
     let body = EnrolBody {
         host: cf.host.to_string(),
-        key: cf.key.to_string(),
+        key: cf.key.clone().unwrap(),
     };
 
-    loop {
-        match c.enrol().body(&body).send().await {
-            Ok(_) => {
-                println!("ok");
-                return Ok(());
-            }
-            /*
-             * XXX progenitor needs a real error type
-             *
-            Err(Error::ResponseError(e)) => {
-                let status = e.status.as_u16();
-                if status >= 400 && status <= 499 {
-                    bail!("request error; giving up: {:?}", e);
-                } else {
-                    eprintln!("request error; retrying: {:?}", e);
-                }
-            }
-             * XXX
-             */
-            Err(e) => {
-                eprintln!("other error; retrying: {:?}", e);
-            }
-        }
+    Policy::new()
+        .deadline(cf.retry_deadline())
+        .run("enrolment", || c.enrol().body(&body).send())
+        .await?;
 
-        sleep_ms(1000);
-    }
+    println!("ok");
+    Ok(())
 }
 
 async fn cmd_ping(mut l: Level<()>) -> Result<()> {
@@ -135,40 +226,81 @@ async fn cmd_ping(mut l: Level<()>) -> Result<()> {
         .ok_or_else(|| anyhow!("no configuration file; enrol first"))?;
     let c = make_client(cf)?;
 
-    loop {
-        match c.ping().send().await {
-            Ok(p) => {
-                if p.host != cf.host {
-                    bail!("remote host {} != local host {}", p.host, cf.host);
-                }
-                println!("ok, host \"{}\"", p.host);
-                return Ok(());
-            }
-            /*
-             * XXX progenitor needs a real error type
-             *
-            Err(Error::ResponseError(e)) => {
-                let status = e.status.as_u16();
-                if status == 403 || status == 401 {
-                    if !authfail_report {
-                        eprintln!("auth failure; waiting for approval");
-                        authfail_report = true;
-                    }
-                } else if status >= 400 && status <= 499 {
-                    bail!("request error; giving up: {:?}", e);
-                } else {
-                    eprintln!("request error; retrying: {:?}", e);
-                }
-            }
-             * XXX
-             */
-            Err(e) => {
-                eprintln!("other error; retrying: {:?}", e);
-            }
-        }
+    /*
+     * A newly enrolled host cannot authenticate until an administrator has
+     * approved it, so we wait for that rather than giving up.
+     */
+    let p = Policy::new()
+        .deadline(cf.retry_deadline())
+        .wait_for_auth()
+        .run("ping", || c.ping().send())
+        .await?;
+
+    if p.host != cf.host {
+        bail!("remote host {} != local host {}", p.host, cf.host);
+    }
+    println!("ok, host \"{}\"", p.host);
+    Ok(())
+}
+
+async fn cmd_rotate(mut l: Level<()>) -> Result<()> {
+    l.optopt(
+        "g",
+        "",
+        "for how long the old key remains valid (default: 3600 seconds)",
+        "SECONDS",
+    );
+
+    let a = args!(l);
+    if !a.args().is_empty() {
+        bad_args!(l, "unexpected arguments");
+    }
+    let grace = if let Some(g) = a.opts().opt_str("g") {
+        Some(
+            g.parse::<u64>()
+                .map_err(|_| anyhow!("invalid grace period"))?,
+        )
+    } else {
+        None
+    };
+
+    let lc = load_config()?;
+    let cf = lc
+        .config
+        .as_ref()
+        .ok_or_else(|| anyhow!("no configuration file; enrol first"))?;
+    if cf.key.is_none() {
+        bail!("configured to use a client certificate; there is no key");
+    }
+    let c = make_client(cf)?;
 
-        sleep_ms(5000);
+    /*
+     * The server will only accept the new key once, so if we cannot save it
+     * locally after a successful rotation we must at least make sure the
+     * operator can see it.  The old key remains valid for the grace period.
+     */
+    let key = genkey(64);
+    let res = c
+        .key_rotate()
+        .body_map(|b| b.key(&key).grace_seconds(grace))
+        .send()
+        .await
+        .map_err(|e| anyhow!("key rotation failed: {:?}", e))?;
+
+    let mut ncf = cf.clone();
+    ncf.key = Some(key.to_string());
+    if let Err(e) = store_file(&lc.path, &ncf, true) {
+        bail!(
+            "could not save new key {} (id {}) to {}: {:?}",
+            key,
+            res.id,
+            lc.path.display(),
+            e
+        );
     }
+
+    println!("ok, new key id \"{}\"", res.id);
+    Ok(())
 }
 
 async fn cmd_exec(l: Level<()>) -> Result<()> {
@@ -184,14 +316,229 @@ async fn cmd_cron(l: Level<()>) -> Result<()> {
     exec_common(l, true).await
 }
 
+/**
+ * Limits on the size of a batch of output records, and on how long we will
+ * hold a record before sending it to the server.
+ */
+const BATCH_MAX_RECORDS: usize = 500;
+const BATCH_MAX_BYTES: usize = 256 * 1024;
+const BATCH_WAIT: Duration = Duration::from_secs(1);
+
+/**
+ * How long we retry each request while a job is running, if not configured,
+ * before we give up on the server and write the rest of the report to the
+ * spool.
+ */
+const EXEC_RETRY_DEADLINE: Duration = Duration::from_secs(60);
+
+/**
+ * How often we tell the server that a job is still running.  The server
+ * considers a job abandoned if it has not heard from us in some time; by
+ * default, fifteen minutes.
+ */
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+ * Delivers the requests that make up a report, either to the server or, once
+ * the server has failed to accept one, to the spool.
+ */
+struct Delivery<'a> {
+    c: &'a Client,
+    silent: bool,
+    policy: Policy,
+    dir: PathBuf,
+    start: ReportStartBody,
+    spool: Option<SpoolFile>,
+    /*
+     * The first delivery failure, if any.  Once the job has started we must
+     * keep collecting its output and wait for it to finish regardless, so
+     * the failure is only reported once the report is complete.
+     */
+    failed: Option<anyhow::Error>,
+}
+
+impl Delivery<'_> {
+    async fn send(&mut self, e: SpoolEntry) {
+        if self.failed.is_some() {
+            return;
+        }
+
+        if let Err(err) = self.try_send(e).await {
+            if !self.silent {
+                println!("ERROR: report delivery failed: {:?}", err);
+            }
+            self.failed = Some(err);
+        }
+    }
+
+    async fn try_send(&mut self, e: SpoolEntry) -> Result<()> {
+        if self.spool.is_none() {
+            let c = self.c;
+            match self
+                .policy
+                .run("report delivery", || spool::deliver(c, &e))
+                .await
+            {
+                Ok(()) => return Ok(()),
+                /*
+                 * The spool is for outages.  If the server has refused the
+                 * request, it will only refuse it again later.
+                 */
+                Err(err) if err.is::<retry::Permanent>() => return Err(err),
+                Err(err) => {
+                    if !self.silent {
+                        println!("ERROR: spooling report: {:?}", err);
+                    }
+                }
+            }
+
+            /*
+             * The spooled report must be complete, so it always begins with
+             * the start of the job even if the server has already seen it.
+             */
+            let mut sf = SpoolFile::create(&self.dir, &self.start.id)?;
+            if !matches!(e, SpoolEntry::Start(_)) {
+                sf.append(&SpoolEntry::Start(self.start.clone()))?;
+            }
+            self.spool = Some(sf);
+        }
+
+        self.spool.as_mut().unwrap().append(&e)
+    }
+
+    /**
+     * Let the server know that the job is still running.  This is only
+     * useful while the server is available, so we try just once, and not at
+     * all once we have resorted to the spool.
+     */
+    async fn heartbeat(&self) {
+        if self.spool.is_some() || self.failed.is_some() {
+            return;
+        }
+
+        let res = self
+            .c
+            .report_heartbeat()
+            .body_map(|b| b.id(self.start.id.clone()))
+            .send()
+            .await;
+        if let Err(e) = res {
+            if !self.silent {
+                println!("ERROR: heartbeat: {:?}", e);
+            }
+        }
+    }
+
+    /**
+     * Called once the whole report has been sent.
+     */
+    async fn complete(self) -> Result<()> {
+        if let Some(err) = self.failed {
+            return Err(err);
+        }
+
+        if let Some(sf) = self.spool {
+            let p = sf.finish()?;
+            if !self.silent {
+                println!(
+                    "server unavailable; report spooled to {}",
+                    p.display()
+                );
+            }
+        } else {
+            /*
+             * The server is evidently available, so this is a good time to
+             * deliver any reports left over from earlier runs.
+             */
+            if let Err(e) = spool::flush(self.c, &self.dir, self.silent).await {
+                if !self.silent {
+                    println!("ERROR: flushing spool: {:?}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_output(&mut self, batch: &mut Vec<OutputRecord>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        self.send(SpoolEntry::Output(ReportOutputBatchBody {
+            id: self.start.id.clone(),
+            records: std::mem::take(batch),
+        }))
+        .await
+    }
+}
+
+/**
+ * How long to wait after SIGTERM before we resort to SIGKILL, if not
+ * specified.
+ */
+const KILL_GRACE: Duration = Duration::from_secs(10);
+
+fn parse_seconds(s: Option<String>, what: &str) -> Result<Option<Duration>> {
+    if let Some(s) = s {
+        match s.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(Some(Duration::from_secs(n))),
+            _ => bail!("invalid {} {:?}", what, s),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
 async fn exec_common(mut l: Level<()>, silent: bool) -> Result<()> {
     l.usage_args(Some("JOBNAME SCRIPT..."));
+    l.optopt(
+        "t",
+        "timeout",
+        "kill the job if it runs for longer than this",
+        "SECONDS",
+    );
+    l.optopt(
+        "k",
+        "kill-after",
+        "after a timeout, wait this long before using SIGKILL (default: 10)",
+        "SECONDS",
+    );
+    l.optflag(
+        "L",
+        "lock",
+        "wait for any previous run of this job to finish before starting",
+    );
+    l.optflag(
+        "N",
+        "no-overlap",
+        "skip this run if a previous run of this job has not finished",
+    );
     let a = args!(l);
 
     if a.args().len() < 1 {
         bad_args!(l, "specify a job name");
     }
 
+    if a.opts().opt_present("L") && a.opts().opt_present("N") {
+        bad_args!(l, "-L and -N are mutually exclusive");
+    }
+    let lock_mode = if a.opts().opt_present("L") {
+        Some(LockMode::Wait)
+    } else if a.opts().opt_present("N") {
+        Some(LockMode::Skip)
+    } else {
+        None
+    };
+    let grace = parse_seconds(a.opts().opt_str("k"), "grace period")?;
+    let timeout =
+        parse_seconds(a.opts().opt_str("t"), "timeout")?.map(|after| {
+            exec::Timeout {
+                after,
+                grace: grace.unwrap_or(KILL_GRACE),
+            }
+        });
+
     let job = a.args()[0].to_string();
     let script = a
         .args()
@@ -211,75 +558,182 @@ async fn exec_common(mut l: Level<()>, silent: bool) -> Result<()> {
         .ok_or_else(|| anyhow!("no configuration file; enrol first"))?;
     let c = make_client(cf)?;
 
-    let id = ReportId::builder()
-        .host(&cf.host)
-        .job(&job)
-        .uuid(genkey(32))
-        .pid(std::process::id())
-        .time(Utc::now());
+    /*
+     * The lock is held until we return, by which time the job has finished.
+     */
+    let lock = if let Some(mode) = lock_mode {
+        Some(lock::lock(&lock_dir(cf)?, &job, mode)?)
+    } else {
+        None
+    };
+
+    let id = ReportId {
+        host: cf.host.to_string(),
+        job: job.to_string(),
+        uuid: genkey(32),
+        pid: std::process::id(),
+        time: Utc::now(),
+    };
 
     let start_time = Utc::now();
-    let rx = exec::run(&["/usr/bin/bash", "-c", &script])?;
+    let mut d = Delivery {
+        c: &c,
+        silent,
+        policy: Policy::new()
+            .deadline(Some(cf.retry_deadline().unwrap_or(EXEC_RETRY_DEADLINE)))
+            .silent(silent),
+        dir: spool_dir(cf)?,
+        start: ReportStartBody {
+            id: id.clone(),
+            script: script.to_string(),
+            start_time,
+        },
+        spool: None,
+        failed: None,
+    };
+
+    if let Some(None) = lock {
+        /*
+         * A previous run still holds the lock.  Record that we skipped this
+         * run, so that the overlap is visible.
+         */
+        if !silent {
+            println!("job \"{}\" is still running; skipping", job);
+        }
+
+        d.send(SpoolEntry::Start(d.start.clone())).await;
+        d.send_output(&mut vec![OutputRecord {
+            time: Utc::now(),
+            stream: "error".to_string(),
+            msg: "skipped: a previous run of this job has not finished"
+                .to_string(),
+            seq: Some(0),
+        }])
+        .await;
+        d.send(SpoolEntry::Finish(ReportFinishBody {
+            id: id.clone(),
+            duration_millis: 0,
+            end_time: Utc::now(),
+            exit_status: 0,
+            outcome: ReportOutcome::Skipped,
+            rusage: None,
+            termination: None,
+        }))
+        .await;
+
+        return d.complete().await;
+    }
+
+    let rx = exec::run(&["/usr/bin/bash", "-c", &script], timeout)?;
 
     /*
      * Report that the job has started to the server:
      */
-    let body = ReportStartBody::builder()
-        .id(id.clone())
-        .script(&script)
-        .start_time(start_time);
+    d.send(SpoolEntry::Start(d.start.clone())).await;
+
+    /*
+     * Output records are collected into batches, which are sent once they
+     * are large enough or once the oldest record in the batch has waited
+     * long enough.  Each record carries the sequence number assigned as it
+     * was read, so that the server can discard any it has already stored if
+     * we send a batch again.
+     */
+    let mut batch: Vec<OutputRecord> = Vec::new();
+    let mut batch_bytes = 0;
+    let mut batch_start = Instant::now();
+    /*
+     * The server will only abandon a report once it has had a heartbeat for
+     * it, so we send the first one straight away.
+     */
+    let mut heartbeat = Instant::now();
 
     loop {
-        if let Err(e) = c.report_start().body(body.clone()).send().await {
-            if !silent {
-                println!("ERROR: {:?}", e);
-            }
-            sleep_ms(1000);
-            continue;
+        /*
+         * A job that produces output steadily may never leave us waiting
+         * long enough for the receive to time out, so we check for overdue
+         * work each time around the loop.
+         */
+        if !batch.is_empty() && batch_start.elapsed() >= BATCH_WAIT {
+            d.send_output(&mut batch).await;
+            batch_bytes = 0;
+        }
+        if Instant::now() >= heartbeat {
+            d.heartbeat().await;
+            heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
         }
-        break;
-    }
 
-    loop {
-        match rx.recv()? {
-            Activity::Output(o) => loop {
-                let res = c
-                    .report_output()
-                    .body_map(|b| b.id(id.clone()).record(o.to_record()))
-                    .send()
-                    .await;
-                if let Err(e) = res {
-                    if !silent {
-                        println!("ERROR: {:?}", e);
-                    }
-                    sleep_ms(1000);
-                    continue;
+        let mut wait = heartbeat.saturating_duration_since(Instant::now());
+        if !batch.is_empty() {
+            wait = wait.min(BATCH_WAIT.saturating_sub(batch_start.elapsed()));
+        }
+
+        let a = match rx.recv_timeout(wait) {
+            Ok(a) => a,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                bail!("child activity channel disconnected");
+            }
+        };
+
+        match a {
+            Activity::Output(o) => {
+                if batch.is_empty() {
+                    batch_start = Instant::now();
                 }
-                break;
-            },
-            Activity::Exit(ed) => loop {
-                let res = c
-                    .report_finish()
-                    .body_map(|b| {
-                        b.id(id.clone())
-                            .duration_millis(ed.duration_ms)
-                            .end_time(ed.when)
-                            .exit_status(ed.code)
-                    })
-                    .send()
-                    .await;
-                if let Err(e) = res {
-                    if !silent {
-                        println!("ERROR: {:?}", e);
-                    }
-                    sleep_ms(1000);
-                    continue;
+                let record = o.to_record();
+                batch_bytes += record.msg.len();
+                batch.push(record);
+
+                if batch.len() >= BATCH_MAX_RECORDS
+                    || batch_bytes >= BATCH_MAX_BYTES
+                {
+                    d.send_output(&mut batch).await;
+                    batch_bytes = 0;
                 }
-                break;
-            },
+            }
+            Activity::Exit(ed) => {
+                /*
+                 * Make sure all of the output has arrived before we report
+                 * that the job is complete.
+                 */
+                d.send_output(&mut batch).await;
+                batch_bytes = 0;
+
+                d.send(SpoolEntry::Finish(ReportFinishBody {
+                    id: id.clone(),
+                    duration_millis: ed.duration_ms,
+                    end_time: ed.when,
+                    exit_status: ed.code,
+                    outcome: if ed.timed_out {
+                        ReportOutcome::TimedOut
+                    } else {
+                        ReportOutcome::Completed
+                    },
+                    rusage: ed.rusage,
+                    termination: Some(ed.termination),
+                }))
+                .await;
+            }
             Activity::Complete => {
-                break Ok(());
+                break;
             }
         }
     }
+
+    d.complete().await
+}
+
+async fn cmd_flush(mut l: Level<()>) -> Result<()> {
+    no_args!(l);
+
+    let lc = load_config()?;
+    let cf = lc
+        .config
+        .as_ref()
+        .ok_or_else(|| anyhow!("no configuration file; enrol first"))?;
+    let c = make_client(cf)?;
+
+    let n = spool::flush(&c, &spool_dir(cf)?, false).await?;
+    println!("ok, {} spooled reports delivered", n);
+    Ok(())
 }
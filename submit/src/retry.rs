@@ -0,0 +1,149 @@
+/*
+ * A policy for retrying requests to the keeper server.  Between attempts we
+ * wait for an exponentially increasing, randomly perturbed, period so that a
+ * fleet of hosts does not descend on a recovering server all at once.
+ */
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use keeper_openapi::types::Error;
+use rand::{thread_rng, Rng};
+
+pub type ApiError = keeper_openapi::Error<Error>;
+
+/**
+ * Errors that will not go away if we make the same request again.  Unless
+ * "wait_for_auth" is set, authentication failures are included.
+ */
+pub fn permanent(e: &ApiError, wait_for_auth: bool) -> bool {
+    match e.status().map(|s| s.as_u16()) {
+        Some(401 | 403) => !wait_for_auth,
+        Some(400 | 409) => true,
+        _ => false,
+    }
+}
+
+/**
+ * The server has refused a request, and will refuse it again if we retry.
+ */
+#[derive(Debug)]
+pub struct Permanent {
+    pub what: String,
+    pub error: ApiError,
+}
+
+impl std::fmt::Display for Permanent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.what, self.error)
+    }
+}
+
+impl std::error::Error for Permanent {}
+
+pub struct Policy {
+    initial: Duration,
+    max_delay: Duration,
+    deadline: Option<Duration>,
+    wait_for_auth: bool,
+    silent: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::new()
+    }
+}
+
+impl Policy {
+    /**
+     * By default we retry transient failures indefinitely.
+     */
+    pub fn new() -> Policy {
+        Policy {
+            initial: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            deadline: None,
+            wait_for_auth: false,
+            silent: false,
+        }
+    }
+
+    /**
+     * Give up if the request has not succeeded within this period.
+     */
+    pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /**
+     * Treat authentication failures as transient; e.g., while the enrolment
+     * of this host is awaiting approval.
+     */
+    pub fn wait_for_auth(mut self) -> Self {
+        self.wait_for_auth = true;
+        self
+    }
+
+    /**
+     * Do not report failed attempts on stdout.
+     */
+    pub fn silent(mut self, silent: bool) -> Self {
+        self.silent = silent;
+        self
+    }
+
+    /**
+     * Make the request until it succeeds.  If the server refuses it outright,
+     * the error is a Permanent; otherwise, we have run out of time.
+     */
+    pub async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, ApiError>>,
+    {
+        let start = Instant::now();
+        let mut delay = self.initial;
+
+        loop {
+            let e = match f().await {
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+
+            if permanent(&e, self.wait_for_auth) {
+                return Err(Permanent {
+                    what: what.to_string(),
+                    error: e,
+                }
+                .into());
+            }
+
+            /*
+             * Wait for somewhere between half and all of the current delay.
+             */
+            let half = delay / 2;
+            let wait = half + thread_rng().gen_range(Duration::ZERO..=half);
+
+            if let Some(deadline) = self.deadline {
+                if start.elapsed() + wait > deadline {
+                    bail!("{} failed; giving up: {}", what, e);
+                }
+            }
+
+            if !self.silent {
+                println!(
+                    "ERROR: {} failed; retrying in {}ms: {}",
+                    what,
+                    wait.as_millis(),
+                    e
+                );
+            }
+
+            tokio::time::sleep(wait).await;
+            delay = (delay * 2).min(self.max_delay);
+        }
+    }
+}
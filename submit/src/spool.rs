@@ -0,0 +1,236 @@
+/*
+ * When the keeper server cannot be reached, reports are written to a local
+ * spool directory instead so that the job can finish without waiting for the
+ * server.  Each spooled report is a file containing the requests we would
+ * have made, one per line, which are replayed later by "keeper-submit flush".
+ * The server ignores requests it has already seen for a report, so replaying
+ * a report that was partially delivered is safe.
+ *
+ * While a job is running, its spool file has an ".active" suffix; it is
+ * renamed once the job has finished, and only then is it eligible to be
+ * replayed.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use keeper_openapi::{types::*, Client};
+use serde::{Deserialize, Serialize};
+
+use crate::retry::permanent;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SpoolEntry {
+    Start(ReportStartBody),
+    Output(ReportOutputBatchBody),
+    Finish(ReportFinishBody),
+}
+
+pub struct SpoolFile {
+    active: PathBuf,
+    done: PathBuf,
+    f: File,
+}
+
+impl SpoolFile {
+    pub fn create(dir: &Path, id: &ReportId) -> Result<SpoolFile> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating spool {}", dir.display()))?;
+
+        let name = format!("{}-{}", id.time.timestamp_millis(), id.uuid);
+        let done = dir.join(format!("{}.ndjson", name));
+        let active = dir.join(format!("{}.ndjson.active", name));
+
+        let f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&active)
+            .with_context(|| format!("creating {}", active.display()))?;
+
+        Ok(SpoolFile { active, done, f })
+    }
+
+    pub fn append(&mut self, e: &SpoolEntry) -> Result<()> {
+        let mut buf = serde_json::to_vec(e)?;
+        buf.push(b'\n');
+        self.f.write_all(&buf)?;
+        Ok(())
+    }
+
+    /**
+     * Mark the report as complete, so that it may be replayed.
+     */
+    pub fn finish(self) -> Result<PathBuf> {
+        self.f.sync_all()?;
+        std::fs::rename(&self.active, &self.done)?;
+        Ok(self.done)
+    }
+}
+
+fn read_entries(p: &Path) -> Result<Vec<SpoolEntry>> {
+    let br = BufReader::new(File::open(p)?);
+
+    let mut out = Vec::new();
+    for l in br.lines() {
+        let l = l?;
+        if l.trim().is_empty() {
+            continue;
+        }
+        out.push(serde_json::from_str(&l)?);
+    }
+    Ok(out)
+}
+
+/**
+ * Make the request recorded in a spool entry.
+ */
+pub async fn deliver(
+    c: &Client,
+    e: &SpoolEntry,
+) -> std::result::Result<(), keeper_openapi::Error<Error>> {
+    match e {
+        SpoolEntry::Start(b) => {
+            c.report_start().body(b.clone()).send().await?;
+        }
+        SpoolEntry::Output(b) => {
+            c.report_output_batch().body(b.clone()).send().await?;
+        }
+        SpoolEntry::Finish(b) => {
+            c.report_finish().body(b.clone()).send().await?;
+        }
+    }
+    Ok(())
+}
+
+enum Replay {
+    Delivered,
+    /**
+     * The server has already completed this report, or another report
+     * exists for the same time; there is nothing more to do with it.
+     */
+    Conflict(String),
+    /**
+     * The server will never accept this report, but it may be of interest to
+     * the operator.
+     */
+    Rejected(String),
+}
+
+async fn replay(c: &Client, entries: &[SpoolEntry]) -> Result<Replay> {
+    for e in entries.iter() {
+        match deliver(c, e).await {
+            Ok(()) => (),
+            Err(e) if e.status().map(|s| s.as_u16()) == Some(409) => {
+                return Ok(Replay::Conflict(e.to_string()));
+            }
+            /*
+             * An authentication failure is not a problem with this report in
+             * particular, so we stop as we would for an outage, rather than
+             * setting aside every report in the spool.
+             */
+            Err(e) if permanent(&e, true) => {
+                return Ok(Replay::Rejected(e.to_string()));
+            }
+            Err(e) => bail!("{}", e),
+        }
+    }
+
+    Ok(Replay::Delivered)
+}
+
+/**
+ * Replay all of the complete reports in the spool directory, oldest first.
+ * We stop at the first report that cannot be delivered, as the server is
+ * likely still unavailable.  Reports the server refuses outright are renamed
+ * with a ".rejected" suffix, so that they do not hold up the rest of the
+ * spool.  Returns the number of reports delivered.
+ */
+pub async fn flush(c: &Client, dir: &Path, silent: bool) -> Result<usize> {
+    let rd = match std::fs::read_dir(dir) {
+        Ok(rd) => rd,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => bail!("reading spool {}: {:?}", dir.display(), e),
+    };
+
+    let mut files = Vec::new();
+    for ent in rd {
+        let p = ent?.path();
+        if p.extension().and_then(|s| s.to_str()) == Some("ndjson") {
+            files.push(p);
+        }
+    }
+    files.sort();
+
+    let mut count = 0;
+    for p in files {
+        let entries = match read_entries(&p) {
+            Ok(entries) => entries,
+            Err(e)
+                if e.downcast_ref::<std::io::Error>().map(|e| e.kind())
+                    == Some(ErrorKind::NotFound) =>
+            {
+                /*
+                 * Another process has flushed this report already.
+                 */
+                continue;
+            }
+            Err(e) => {
+                if !silent {
+                    eprintln!("WARNING: skipping {}: {:?}", p.display(), e);
+                }
+                continue;
+            }
+        };
+
+        match replay(c, &entries)
+            .await
+            .with_context(|| format!("replaying {}", p.display()))?
+        {
+            Replay::Delivered => count += 1,
+            Replay::Conflict(msg) => {
+                if !silent {
+                    eprintln!(
+                        "WARNING: discarding {}: server rejected report: {}",
+                        p.display(),
+                        msg
+                    );
+                }
+            }
+            Replay::Rejected(msg) => {
+                let mut q = p.clone().into_os_string();
+                q.push(".rejected");
+                let q = PathBuf::from(q);
+
+                if !silent {
+                    eprintln!(
+                        "WARNING: setting aside {} as {}: server rejected \
+                        report: {}",
+                        p.display(),
+                        q.display(),
+                        msg
+                    );
+                }
+
+                match std::fs::rename(&p, &q) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        bail!("renaming {}: {:?}", p.display(), e);
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+        }
+
+        match std::fs::remove_file(&p) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                bail!("removing {}: {:?}", p.display(), e);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(count)
+}